@@ -229,9 +229,17 @@ pub async fn generate_ollama(ctx: Context<'_>, prompt: String) -> Result<(), Err
     drop(users);
 
     let db_clone = ctx.data().funboy_db.clone();
-    let interpreted_prompt = tokio::task::spawn_blocking(move || interp_input(prompt, db_clone))
-        .await?
-        .await;
+    let guild_id = ctx.template_guild_id();
+    let channel_id = ctx.channel_id().get();
+    let interpreted_prompt = interp_input(
+        prompt,
+        db_clone,
+        guild_id,
+        channel_id,
+        user_id.get(),
+        ctx.data().fsl_budget,
+    )
+    .await;
 
     let result: Result<(), Error> = {
         match interpreted_prompt {
@@ -1,17 +1,27 @@
 use core::panic;
 
+use poise::CreateReply;
+use rand::Rng;
+use serenity::all::{Attachment, CreateAttachment};
+use tokio::sync::MutexGuard;
+
 use crate::io_utils::context_extension::MessageListFormatter;
 use crate::io_utils::custom_components::{
     create_confirmation_interaction, CANCEL_BUTTON_ID, CONFIRM_BUTTON_ID,
 };
 use crate::io_utils::discord_message_format;
-use crate::io_utils::input_interp::interp_input;
+use crate::io_utils::discord_message_format::ellipsize_if_long;
+use crate::io_utils::input_interp::{interp_input_explained, Explanation, ExplanationNode};
 use crate::{
     io_utils::{
         change_log::OutputLog,
         context_extension::{ContextExtension, MESSAGE_BYTE_LIMIT},
         discord_message_format::vectorize_input,
     },
+    storage::{
+        template_database::{ChangeEntry, ChangeField, ChangeRecord, FunboyDatabase},
+        template_export::TemplateExport,
+    },
     Context, Error,
 };
 
@@ -24,9 +34,93 @@ const ERROR_DATABASE_QUERY: &str = "Error: There was a problem querying the data
 const ERROR_GENERATION_FAILED: &str = "Error: Text generation failed.";
 const ERROR_TEMPLATE_TOO_LARGE: &str = "Error: Template was too large.";
 const ERROR_SUB_TOO_LARGE: &str = "Error: Substitute was too large.";
+const ERROR_TEMPLATE_LOCKED: &str =
+    "Error: This template is locked. Only its owner or members with the Manage Server permission can change it.";
+const ERROR_NOT_TEMPLATE_OWNER: &str =
+    "Error: Only the owner of this template or members with the Manage Server permission can lock or unlock it.";
+const HISTORY_LIMIT: usize = 25;
+const SEARCH_LIMIT: usize = 100;
+const EXPLANATION_OUTPUT_LIMIT: usize = 100;
+/// Largest integer Discord accepts as an option, so every printed seed can be passed back in.
+const SEED_LIMIT: u64 = (1 << 53) - 1;
+const IMPORT_BYTE_LIMIT: u32 = 8 * 1024 * 1024;
+const EXPORT_FILE_NAME: &str = "templates.json";
+const TRACERY_FILE_NAME: &str = "grammar.json";
 const REMOVE_TEMPLATE_WARNING: &str =
     "Are you sure you want to remove this template? All of it's substitutes will be deleted.";
 
+/// Holds the database if the author may modify every template, telling them why not when they
+/// can't.
+///
+/// The templates are checked while the database is held so none of them can be locked before the
+/// caller modifies them. Unlocked templates and templates that don't exist yet can be modified by
+/// anyone.
+async fn lock_templates_for_author<'a>(
+    ctx: Context<'a>,
+    templates: &[&str],
+) -> Result<Option<MutexGuard<'a, FunboyDatabase>>, Error> {
+    lock_affected_templates_for_author(ctx, |_| {
+        Ok(templates
+            .iter()
+            .map(|template| template.to_string())
+            .collect())
+    })
+    .await
+}
+
+/// Holds the database if the author may modify every template `affected` finds, like
+/// [`lock_templates_for_author`].
+///
+/// `affected` is called while the database is held, for changes that touch templates other than
+/// the one they name.
+async fn lock_affected_templates_for_author<'a>(
+    ctx: Context<'a>,
+    affected: impl FnOnce(&FunboyDatabase) -> rusqlite::Result<Vec<String>>,
+) -> Result<Option<MutexGuard<'a, FunboyDatabase>>, Error> {
+    let guild_id = ctx.template_guild_id();
+    let db = ctx.data().funboy_db.lock().await;
+    let mut locked = false;
+
+    for template in affected(&db)? {
+        match db.get_template_info(guild_id, &template) {
+            Ok(info) => locked |= info.locked && info.owner_id != Some(ctx.author().id.get()),
+            Err(rusqlite::Error::QueryReturnedNoRows) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if !locked {
+        return Ok(Some(db));
+    }
+
+    // Managers may modify locked templates, so the database doesn't need to be held while their
+    // permissions are checked.
+    drop(db);
+
+    if ctx.author_can_manage_guild().await? {
+        Ok(Some(ctx.data().funboy_db.lock().await))
+    } else {
+        ctx.say_ephemeral(ERROR_TEMPLATE_LOCKED).await?;
+        Ok(None)
+    }
+}
+
+/// Holds the database if the author may modify the template like [`lock_templates_for_author`].
+async fn lock_template_for_author<'a>(
+    ctx: Context<'a>,
+    template: &str,
+) -> Result<Option<MutexGuard<'a, FunboyDatabase>>, Error> {
+    lock_templates_for_author(ctx, &[template]).await
+}
+
+/// Checks if the author may modify every template before asking them to confirm a change.
+///
+/// The database isn't held while they decide, so the templates must be checked again with
+/// [`lock_templates_for_author`] before they are modified.
+async fn check_template_rights(ctx: Context<'_>, templates: &[&str]) -> Result<bool, Error> {
+    Ok(lock_templates_for_author(ctx, templates).await?.is_some())
+}
+
 /// Add and create templates with multiple substitutes
 ///
 /// A template is an alias that refers to one or more substitutes
@@ -60,8 +154,11 @@ pub async fn add_subs(
         }
     }
 
-    let mut db = ctx.data().funboy_db.lock().await;
-    match db.insert_subs(&template, Some(&subs)) {
+    let guild_id = ctx.template_guild_id();
+    let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+        return Ok(());
+    };
+    match db.insert_subs(guild_id, ctx.author().id.get(), &template, Some(&subs)) {
         Err(e) => {
             ctx.say_ephemeral(&e.to_string()).await?;
         }
@@ -109,9 +206,12 @@ pub async fn add_sub(ctx: Context<'_>, template: String, substitute: String) ->
         return Ok(());
     }
 
-    let mut db = ctx.data().funboy_db.lock().await;
+    let guild_id = ctx.template_guild_id();
+    let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+        return Ok(());
+    };
 
-    match db.insert_sub(&template, &substitute) {
+    match db.insert_sub(guild_id, ctx.author().id.get(), &template, &substitute) {
         Err(e) => {
             ctx.say_ephemeral(&e.to_string()).await?;
         }
@@ -150,12 +250,15 @@ pub async fn copy_subs(
     from_template: String,
     to_template: String,
 ) -> Result<(), Error> {
-    let mut db = ctx.data().funboy_db.lock().await;
+    let guild_id = ctx.template_guild_id();
+    let Some(mut db) = lock_template_for_author(ctx, &to_template).await? else {
+        return Ok(());
+    };
 
-    if let Ok(subs) = db.get_subs(&from_template) {
+    if let Ok(subs) = db.get_subs(guild_id, &from_template) {
         let subs: Vec<&str> = subs.iter().map(|s| s.as_str()).collect();
 
-        match db.insert_subs(&to_template, Some(&subs)) {
+        match db.insert_subs(guild_id, ctx.author().id.get(), &to_template, Some(&subs)) {
             Err(e) => {
                 ctx.say_ephemeral(&e.to_string()).await?;
             }
@@ -196,12 +299,16 @@ pub async fn copy_subs(
 
 /// Remove a template
 ///
-/// **Warning:** This command will permanently delete a template and all of it's substitutes
-/// this action cannot be undone!
+/// **Warning:** This command will delete a template and all of it's substitutes. Use **/undo**
+/// right after to restore them.
 ///
 /// Example usage: **/remove_template** template: **fruit**
 #[poise::command(slash_command, prefix_command, category = "Text substitution")]
 pub async fn remove_template(ctx: Context<'_>, template: String) -> Result<(), Error> {
+    if !check_template_rights(ctx, &[&template]).await? {
+        return Ok(());
+    }
+
     match create_confirmation_interaction(ctx, REMOVE_TEMPLATE_WARNING, 30).await? {
         Some(interaction) => match interaction.data.custom_id.as_str() {
             CANCEL_BUTTON_ID => {
@@ -223,8 +330,11 @@ pub async fn remove_template(ctx: Context<'_>, template: String) -> Result<(), E
                     )
                     .await?;
 
-                let mut db = ctx.data().funboy_db.lock().await;
-                match db.remove_template(&template) {
+                let guild_id = ctx.template_guild_id();
+                let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+                    return Ok(());
+                };
+                match db.remove_template(guild_id, ctx.author().id.get(), &template) {
                     Err(e) => match e {
                         rusqlite::Error::QueryReturnedNoRows => {
                             ctx.say_ephemeral(&format!(
@@ -273,9 +383,12 @@ pub async fn remove_sub(
     template: String,
     substitute: String,
 ) -> Result<(), Error> {
-    let mut db = ctx.data().funboy_db.lock().await;
+    let guild_id = ctx.template_guild_id();
+    let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+        return Ok(());
+    };
 
-    match db.remove_sub(&template, &substitute) {
+    match db.remove_sub(guild_id, ctx.author().id.get(), &template, &substitute) {
         Err(e) => match e {
             rusqlite::Error::QueryReturnedNoRows => {
                 ctx.say_ephemeral(&format!(
@@ -320,9 +433,12 @@ pub async fn remove_sub(
 /// Example usage: **/remove_sub** template: **fruit** id: **1234**
 #[poise::command(slash_command, prefix_command, category = "Text substitution")]
 pub async fn remove_sub_by_id(ctx: Context<'_>, template: String, id: usize) -> Result<(), Error> {
-    let mut db = ctx.data().funboy_db.lock().await;
+    let guild_id = ctx.template_guild_id();
+    let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+        return Ok(());
+    };
 
-    match db.remove_sub_by_id(&template, id) {
+    match db.remove_sub_by_id(guild_id, ctx.author().id.get(), &template, id) {
         Err(e) => match e {
             rusqlite::Error::QueryReturnedNoRows => {
                 ctx.say_ephemeral(&format!(
@@ -374,8 +490,11 @@ pub async fn remove_subs(
 ) -> Result<(), Error> {
     let subs_to_remove: Vec<&str> = vectorize_input(substitutes.as_str());
 
-    let mut db = ctx.data().funboy_db.lock().await;
-    match db.remove_subs(&template, &subs_to_remove) {
+    let guild_id = ctx.template_guild_id();
+    let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+        return Ok(());
+    };
+    match db.remove_subs(guild_id, ctx.author().id.get(), &template, &subs_to_remove) {
         Err(e) => match e {
             rusqlite::Error::QueryReturnedNoRows => {
                 ctx.say_ephemeral(&format!(
@@ -442,8 +561,11 @@ pub async fn remove_subs_by_id(
             .await?;
     }
 
-    let mut db = ctx.data().funboy_db.lock().await;
-    match db.remove_subs_by_id(&template, &subs_to_remove) {
+    let guild_id = ctx.template_guild_id();
+    let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+        return Ok(());
+    };
+    match db.remove_subs_by_id(guild_id, ctx.author().id.get(), &template, &subs_to_remove) {
         Err(e) => match e {
             rusqlite::Error::QueryReturnedNoRows => {
                 ctx.say_ephemeral(&format!(
@@ -511,8 +633,17 @@ pub async fn replace_sub(
             return Ok(());
         }
 
-        let mut db = ctx.data().funboy_db.lock().await;
-        match db.replace_substitute(&template, &old_sub, &new_sub) {
+        let guild_id = ctx.template_guild_id();
+        let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+            return Ok(());
+        };
+        match db.replace_substitute(
+            guild_id,
+            ctx.author().id.get(),
+            &template,
+            &old_sub,
+            &new_sub,
+        ) {
             Err(e) => match e {
                 rusqlite::Error::QueryReturnedNoRows => {
                     ctx.say_ephemeral(&format!(
@@ -567,8 +698,12 @@ pub async fn replace_sub_by_id(
             return Ok(());
         }
 
-        let mut db = ctx.data().funboy_db.lock().await;
-        match db.replace_substitute_by_id(&template, id, &new_sub) {
+        let guild_id = ctx.template_guild_id();
+        let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+            return Ok(());
+        };
+        match db.replace_substitute_by_id(guild_id, ctx.author().id.get(), &template, id, &new_sub)
+        {
             Err(e) => match e {
                 rusqlite::Error::QueryReturnedNoRows => {
                     ctx.say_ephemeral(&format!(
@@ -607,14 +742,83 @@ pub async fn replace_sub_by_id(
     Ok(())
 }
 
+/// Set how likely a substitute is to be picked compared to the other substitutes in a template
+///
+/// Substitutes have a weight of 1 by default. A substitute with a weight of 3 is three times as
+/// likely to be picked as one with a weight of 1, and a weight of 0 means it is never picked.
+///
+/// **Tip:** To get the id and current weight of a substitute use the command /list_ids
+///
+/// Example usage: **/set_sub_weight** template: **fruit** id: **1234** weight: **5**
+#[poise::command(slash_command, prefix_command, category = "Text substitution")]
+pub async fn set_sub_weight(
+    ctx: Context<'_>,
+    template: String,
+    id: usize,
+    weight: u32,
+) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
+    let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+        return Ok(());
+    };
+
+    match db.set_sub_weight(guild_id, ctx.author().id.get(), &template, id, weight) {
+        Err(e) => match e {
+            rusqlite::Error::QueryReturnedNoRows => {
+                ctx.say_ephemeral(&format!(
+                    "No template named **\"**{}**\"** exists.",
+                    template
+                ))
+                .await?;
+            }
+            _ => {
+                ctx.say_ephemeral(&e.to_string()).await?;
+            }
+        },
+        Ok(result) => {
+            if result {
+                ctx.say_long(
+                    &format!(
+                        "Set weight of substitute with id **{}** in template **\"**{}**\"** to **{}**.",
+                        id, template, weight
+                    )[..],
+                    false,
+                )
+                .await?;
+            } else {
+                ctx.say_long(
+                    &format!(
+                        "No substitute exists in template **\"**{}**\"** with id **{}**.",
+                        template, id
+                    )[..],
+                    true,
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /// Rename a template
 ///
 /// **Tip:** If this template is referenced inside of another template it will also rename
-/// the refernce
+/// the refernce. Only exact references such as **^fruit** or **get_sub("fruit")** are renamed,
+/// **^fruits** is left alone.
+///
+/// Set dry_run to **True** to list the substitutes that would be rewritten without renaming
+/// anything.
 ///
 /// Example usage: **/rename_template** from: **fruit** to: **vegtable**
 #[poise::command(slash_command, prefix_command, category = "Text substitution")]
-pub async fn rename_template(ctx: Context<'_>, from: String, to: String) -> Result<(), Error> {
+pub async fn rename_template(
+    ctx: Context<'_>,
+    from: String,
+    to: String,
+    #[description = "List the substitutes that would be rewritten without renaming anything"]
+    dry_run: Option<bool>,
+) -> Result<(), Error> {
     if from.contains(|c: char| !c.is_alphanumeric()) || to.contains(|c: char| !c.is_alphanumeric())
     {
         ctx.say_ephemeral(ERROR_INVALID_TEMPLATE_NAME).await?;
@@ -624,9 +828,26 @@ pub async fn rename_template(ctx: Context<'_>, from: String, to: String) -> Resu
         return Ok(());
     }
 
-    let mut db = ctx.data().funboy_db.lock().await;
+    if dry_run.unwrap_or(false) {
+        return preview_rename_template(ctx, &from, &to).await;
+    }
+
+    let guild_id = ctx.template_guild_id();
+    // Rewriting references modifies the templates containing them as well.
+    let affected = |db: &FunboyDatabase| {
+        let mut templates = vec![from.clone()];
+        for reference in db.preview_rename_template(guild_id, &from, &to)? {
+            if !templates.contains(&reference.template) {
+                templates.push(reference.template);
+            }
+        }
+        Ok(templates)
+    };
+    let Some(mut db) = lock_affected_templates_for_author(ctx, affected).await? else {
+        return Ok(());
+    };
 
-    match db.rename_template(&from, &to) {
+    match db.rename_template(guild_id, ctx.author().id.get(), &from, &to) {
         Err(e) => {
             eprintln!("Error: {}", e);
             ctx.say_ephemeral(&format!("Error: Failed to rename template. Make sure no preexisting templates exist named **{}**.", to))
@@ -649,16 +870,663 @@ pub async fn rename_template(ctx: Context<'_>, from: String, to: String) -> Resu
     Ok(())
 }
 
+async fn preview_rename_template(ctx: Context<'_>, from: &str, to: &str) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
+    let db = ctx.data().funboy_db.lock().await;
+
+    match db.preview_rename_template(guild_id, from, to) {
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral(ERROR_DATABASE_QUERY).await?;
+        }
+        Ok(references) => {
+            if references.is_empty() {
+                ctx.say_ephemeral(&format!("No substitutes reference **\"**{}**\"**.", from))
+                    .await?;
+                return Ok(());
+            }
+
+            let mut results = vec![format!(
+                "Renaming **\"**{}**\"** to **\"**{}**\"** would rewrite {} substitutes:\n",
+                from,
+                to,
+                references.len()
+            )];
+            let mut current_template: Option<&str> = None;
+
+            for reference in &references {
+                if current_template != Some(reference.template.as_str()) {
+                    current_template = Some(reference.template.as_str());
+                    results.push(format!("\n**Template:** {}\n", reference.template));
+                }
+
+                results.push(format!(
+                    "**ID:** {} {} → {}\n",
+                    reference.id, reference.before, reference.after
+                ));
+            }
+
+            ctx.say_list(
+                &results.iter().map(|s| s.as_str()).collect::<Vec<&str>>()[..],
+                true,
+                None,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Allow or prevent this server from reading templates created before templates were per server
+///
+/// Legacy templates can be used in **/generate** and copied with **/copy_subs** but can not be
+/// modified. Templates created in this server take priority over legacy templates of the same name.
+///
+/// Example usage: **/use_legacy_templates** enabled: **True**
+#[poise::command(
+    slash_command,
+    prefix_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    category = "Text substitution"
+)]
+pub async fn use_legacy_templates(ctx: Context<'_>, enabled: bool) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
+    let db = ctx.data().funboy_db.lock().await;
+
+    match db.set_legacy_access(guild_id, enabled) {
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral(ERROR_DATABASE_QUERY).await?;
+        }
+        Ok(_) => {
+            if enabled {
+                ctx.say("Legacy templates are now readable in this server.")
+                    .await?;
+            } else {
+                ctx.say("Legacy templates are no longer readable in this server.")
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Lock or unlock a template
+///
+/// A locked template can only be changed or removed by the member that created it or by members
+/// with the Manage Server permission. Anyone can still use it to generate text.
+///
+/// Example usage: **/lock_template** template: **fruit** locked: **True**
+#[poise::command(slash_command, prefix_command, category = "Text substitution")]
+pub async fn lock_template(ctx: Context<'_>, template: String, locked: bool) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
+    let mut db = ctx.data().funboy_db.lock().await;
+
+    match db.get_template_info(guild_id, &template) {
+        Ok(info) => {
+            // Managers may lock any template, so the database doesn't need to be held while their
+            // permissions are checked.
+            if info.owner_id != Some(ctx.author().id.get()) {
+                drop(db);
+
+                if !ctx.author_can_manage_guild().await? {
+                    ctx.say_ephemeral(ERROR_NOT_TEMPLATE_OWNER).await?;
+                    return Ok(());
+                }
+
+                db = ctx.data().funboy_db.lock().await;
+            }
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            ctx.say_ephemeral(&format!(
+                "No template named **\"**{}**\"** exists.",
+                &template
+            ))
+            .await?;
+            return Ok(());
+        }
+        Err(e) => {
+            ctx.say_ephemeral(&e.to_string()).await?;
+            return Ok(());
+        }
+    }
+
+    match db.set_template_locked(guild_id, ctx.author().id.get(), &template, locked) {
+        Err(e) => {
+            ctx.say_ephemeral(&e.to_string()).await?;
+        }
+        Ok(_) => {
+            if locked {
+                ctx.say(format!("Locked template **\"**{}**\"**.", &template))
+                    .await?;
+            } else {
+                ctx.say(format!("Unlocked template **\"**{}**\"**.", &template))
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Draw substitutes from a template like cards from a deck
+///
+/// In deck mode a substitute isn't picked again until every other substitute has been picked, a
+/// substitute with a weight of 3 is put in the deck 3 times. Each channel draws from its own deck
+/// and the deck is shuffled once it runs out.
+///
+/// Example usage: **/deck_mode** template: **fruit** enabled: **True**
+#[poise::command(slash_command, prefix_command, category = "Text substitution")]
+pub async fn deck_mode(ctx: Context<'_>, template: String, enabled: bool) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
+    let Some(mut db) = lock_template_for_author(ctx, &template).await? else {
+        return Ok(());
+    };
+
+    match db.set_deck_mode(guild_id, ctx.author().id.get(), &template, enabled) {
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral(ERROR_DATABASE_QUERY).await?;
+        }
+        Ok(false) => {
+            ctx.say_ephemeral(&format!(
+                "No template named **\"**{}**\"** exists.",
+                &template
+            ))
+            .await?;
+        }
+        Ok(true) => {
+            if enabled {
+                ctx.say(format!(
+                    "Template **\"**{}**\"** is now drawn like a deck.",
+                    &template
+                ))
+                .await?;
+            } else {
+                ctx.say(format!(
+                    "Template **\"**{}**\"** is now picked at random.",
+                    &template
+                ))
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// File formats templates can be exported to and imported from.
+#[derive(Debug, Default, Clone, Copy, poise::ChoiceParameter)]
+pub enum TemplateFormat {
+    #[default]
+    Funboy,
+    Tracery,
+}
+
+/// Export templates as a JSON file
+///
+/// Leave templates empty to export every template. The file can be loaded into any server with
+/// **/import_templates**.
+///
+/// **Tip:** Choose the **Tracery** format to use your templates as a Tracery grammar. Templates are
+/// written as **#template#** and substitute weights are kept by repeating substitutes.
+///
+/// Example usage: **/export_templates** templates: **noun verb**
+/// Example usage: **/export_templates** format: **Tracery**
+#[poise::command(slash_command, prefix_command, category = "Text substitution")]
+pub async fn export_templates(
+    ctx: Context<'_>,
+    templates: Option<String>,
+    format: Option<TemplateFormat>,
+) -> Result<(), Error> {
+    let names: Option<Vec<&str>> = templates.as_deref().map(vectorize_input);
+
+    if let Some(names) = &names {
+        if names
+            .iter()
+            .any(|name| name.contains(|c: char| !c.is_alphanumeric()))
+        {
+            ctx.say_ephemeral(ERROR_INVALID_TEMPLATE_NAME).await?;
+            return Ok(());
+        }
+    }
+
+    let guild_id = ctx.template_guild_id();
+    let export = ctx
+        .data()
+        .funboy_db
+        .lock()
+        .await
+        .export_templates(guild_id, names.as_deref());
+
+    match export {
+        Ok(export) => {
+            if export.templates.is_empty() {
+                ctx.say_ephemeral(ERROR_NO_TEMPLATES).await?;
+                return Ok(());
+            }
+
+            let (json, file_name) = match format.unwrap_or_default() {
+                TemplateFormat::Funboy => (export.to_json()?, EXPORT_FILE_NAME),
+                TemplateFormat::Tracery => (export.to_tracery()?, TRACERY_FILE_NAME),
+            };
+
+            ctx.send(
+                CreateReply::default()
+                    .content(format!(
+                        "Exported **{}** templates.",
+                        export.templates.len()
+                    ))
+                    .attachment(CreateAttachment::bytes(json.into_bytes(), file_name)),
+            )
+            .await?;
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            ctx.say_ephemeral("Error: One or more of those templates don't exist.")
+                .await?;
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral(ERROR_DATABASE_QUERY).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Import templates from a JSON file created by /export_templates or a Tracery grammar
+///
+/// Substitutes already present in a template are skipped. You will be shown how many templates
+/// and substitutes will be added before anything is changed.
+///
+/// **Tip:** Choose the **Tracery** format to import a Tracery grammar. Each rule becomes a template
+/// and **#rule#** references become **^rule**. Repeated entries become weighted substitutes.
+///
+/// Example usage: **/import_templates** file: **templates.json**
+/// Example usage: **/import_templates** file: **grammar.json** format: **Tracery**
+#[poise::command(slash_command, prefix_command, category = "Text substitution")]
+pub async fn import_templates(
+    ctx: Context<'_>,
+    file: Attachment,
+    format: Option<TemplateFormat>,
+) -> Result<(), Error> {
+    if file.size > IMPORT_BYTE_LIMIT {
+        ctx.say_ephemeral("Error: Template file was too large.")
+            .await?;
+        return Ok(());
+    }
+
+    let contents = file.download().await?;
+    let export = match format.unwrap_or_default() {
+        TemplateFormat::Funboy => TemplateExport::from_json(&contents),
+        TemplateFormat::Tracery => TemplateExport::from_tracery(&contents),
+    };
+
+    let export = match export {
+        Ok(export) => export,
+        Err(e) => {
+            ctx.say_ephemeral(&format!("Error: Invalid template file: {}", e))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    for template in &export.templates {
+        if template.name.len() > INPUT_BYTE_LIMIT {
+            ctx.say_ephemeral(ERROR_TEMPLATE_TOO_LARGE).await?;
+            return Ok(());
+        } else if template.name.is_empty() || template.name.contains(|c: char| !c.is_alphanumeric())
+        {
+            ctx.say_ephemeral(ERROR_INVALID_TEMPLATE_NAME).await?;
+            return Ok(());
+        } else if template
+            .substitutes
+            .iter()
+            .any(|sub| sub.name.len() > INPUT_BYTE_LIMIT)
+        {
+            ctx.say_ephemeral(ERROR_SUB_TOO_LARGE).await?;
+            return Ok(());
+        }
+    }
+
+    let names: Vec<&str> = export.templates.iter().map(|t| t.name.as_str()).collect();
+
+    if !check_template_rights(ctx, &names).await? {
+        return Ok(());
+    }
+
+    let guild_id = ctx.template_guild_id();
+    let preview =
+        ctx.data()
+            .funboy_db
+            .lock()
+            .await
+            .preview_import(guild_id, ctx.author().id.get(), &export);
+
+    let preview = match preview {
+        Ok(preview) => preview,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral(ERROR_DATABASE_QUERY).await?;
+            return Ok(());
+        }
+    };
+
+    if preview.templates == 0 && preview.substitutes == 0 {
+        ctx.say_ephemeral("Every template and substitute in this file is already present.")
+            .await?;
+        return Ok(());
+    }
+
+    let warning = format!(
+        "This will add **{}** new templates and **{}** new substitutes. Are you sure you want to import them?",
+        preview.templates, preview.substitutes
+    );
+
+    match create_confirmation_interaction(ctx, &warning, 30).await? {
+        Some(interaction) => match interaction.data.custom_id.as_str() {
+            CANCEL_BUTTON_ID => {
+                interaction
+                    .create_response(
+                        ctx.http(),
+                        serenity::all::CreateInteractionResponse::Acknowledge,
+                    )
+                    .await?;
+
+                ctx.say_ephemeral("Command to import templates canceled.")
+                    .await?
+            }
+            CONFIRM_BUTTON_ID => {
+                interaction
+                    .create_response(
+                        ctx.http(),
+                        serenity::all::CreateInteractionResponse::Acknowledge,
+                    )
+                    .await?;
+
+                let Some(mut db) = lock_templates_for_author(ctx, &names).await? else {
+                    return Ok(());
+                };
+                match db.import_templates(guild_id, ctx.author().id.get(), &export) {
+                    Err(e) => {
+                        ctx.say_ephemeral(&e.to_string()).await?;
+                    }
+                    Ok(summary) => {
+                        ctx.say(format!(
+                            "Imported **{}** new templates and **{}** new substitutes.",
+                            summary.templates, summary.substitutes
+                        ))
+                        .await?;
+                    }
+                }
+            }
+            _ => {
+                panic!("Incorrect id for import templates confirmation interaction.")
+            }
+        },
+        None => {
+            ctx.say_ephemeral("Timeout: Command to import templates canceled.")
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn format_change_entry(entry: &ChangeEntry) -> String {
+    let noun = match entry.field {
+        ChangeField::Template => "template",
+        ChangeField::Substitute => "substitute",
+        ChangeField::Weight => "weight",
+        ChangeField::Locked => "locked",
+        ChangeField::Deck => "deck mode",
+    };
+
+    match (&entry.before, &entry.after) {
+        (None, Some(after)) => format!("Added {} **\"**{}**\"**", noun, after),
+        (Some(before), None) => format!("Removed {} **\"**{}**\"**", noun, before),
+        (Some(before), Some(after)) => format!(
+            "Changed {} **\"**{}**\"** to **\"**{}**\"**",
+            noun, before, after
+        ),
+        (None, None) => format!("Changed {}", noun),
+    }
+}
+
+fn format_change(change: &ChangeRecord) -> String {
+    let mut message = format!(
+        "**<t:{}:f>** <@{}> used **{}**{}\n",
+        change.timestamp,
+        change.user_id,
+        change.action,
+        if change.undone { " *(undone)*" } else { "" }
+    );
+
+    for entry in &change.entries {
+        message.push_str(&format_change_entry(entry));
+        message.push('\n');
+    }
+
+    message.push('\n');
+    message
+}
+
+/// Show recent changes made to a template
+///
+/// Changes made to the template before it was renamed are included.
+///
+/// Example usage: **/history** template: **fruit**
+#[poise::command(slash_command, prefix_command, category = "Text substitution")]
+pub async fn history(ctx: Context<'_>, template: String) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
+    let db = ctx.data().funboy_db.lock().await;
+
+    match db.get_history(guild_id, &template, HISTORY_LIMIT) {
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral(ERROR_DATABASE_QUERY).await?;
+        }
+        Ok(changes) => {
+            if changes.is_empty() {
+                ctx.say_ephemeral(&format!(
+                    "No changes recorded for template **\"**{}**\"**.",
+                    &template
+                ))
+                .await?;
+            } else {
+                let changes: Vec<String> = changes.iter().map(format_change).collect();
+
+                ctx.say_list(
+                    &changes.iter().map(|s| s.as_str()).collect::<Vec<&str>>()[..],
+                    true,
+                    None,
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Undo your last change to a template
+///
+/// Each use undoes the change before the previous one.
+///
+/// Example usage: **/undo**
+#[poise::command(slash_command, prefix_command, category = "Text substitution")]
+pub async fn undo(ctx: Context<'_>) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
+
+    let change = ctx
+        .data()
+        .funboy_db
+        .lock()
+        .await
+        .get_last_change(guild_id, ctx.author().id.get());
+
+    let change = match change {
+        Ok(Some(change)) => change,
+        Ok(None) => {
+            ctx.say_ephemeral("You have no changes to undo.").await?;
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral(ERROR_DATABASE_QUERY).await?;
+            return Ok(());
+        }
+    };
+
+    let affected = |db: &FunboyDatabase| db.get_change_templates(guild_id, &change);
+    let Some(mut db) = lock_affected_templates_for_author(ctx, affected).await? else {
+        return Ok(());
+    };
+
+    match db.undo_change(guild_id, &change) {
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral("Error: Failed to undo your last change.")
+                .await?;
+        }
+        Ok(false) => {
+            ctx.say_ephemeral("Nothing was undone, your last change was already undone.")
+                .await?;
+        }
+        Ok(true) => {
+            ctx.say_long(&format!("Undid:\n{}", format_change(&change)), false)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Search every template for substitutes containing all of the given words
+///
+/// Results are grouped by template and include each substitute's ID.
+///
+/// Example usage: **/search_subs** query: **raven**
+#[poise::command(slash_command, prefix_command, category = "Text substitution")]
+pub async fn search_subs(ctx: Context<'_>, query: String) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
+    let db = ctx.data().funboy_db.lock().await;
+
+    match db.search_subs(guild_id, &query, SEARCH_LIMIT) {
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral(ERROR_DATABASE_QUERY).await?;
+        }
+        Ok(matches) => {
+            if matches.is_empty() {
+                ctx.say_ephemeral(&format!("No substitutes matched **\"**{}**\"**.", &query))
+                    .await?;
+                return Ok(());
+            }
+
+            let mut results: Vec<String> = Vec::new();
+            let mut current_template: Option<&str> = None;
+
+            for sub in &matches {
+                if current_template != Some(sub.template.as_str()) {
+                    current_template = Some(sub.template.as_str());
+                    results.push(format!("\n**Template:** {}\n", sub.template));
+                }
+
+                results.push(format!("**ID:** {} {}\n", sub.id, sub.name));
+            }
+
+            ctx.say_list(
+                &results.iter().map(|s| s.as_str()).collect::<Vec<&str>>()[..],
+                true,
+                None,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check this server's templates for problems
+///
+/// Reports references to templates that don't exist, which are left in the output as is,
+/// templates without any substitutes that can be picked, templates no other template references,
+/// and templates that reference each other in a loop, which stops **/generate** with an error.
+///
+/// Example usage: **/lint_templates**
+#[poise::command(slash_command, prefix_command, category = "Text substitution")]
+pub async fn lint_templates(ctx: Context<'_>) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
+    let db = ctx.data().funboy_db.lock().await;
+
+    match db.lint_templates(guild_id) {
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ctx.say_ephemeral(ERROR_DATABASE_QUERY).await?;
+        }
+        Ok(report) => {
+            if report.is_empty() {
+                ctx.say_ephemeral("No problems found.").await?;
+                return Ok(());
+            }
+
+            let mut results: Vec<String> = Vec::new();
+
+            if !report.dangling.is_empty() {
+                results.push("\n**Dangling references:**\n".to_string());
+                for reference in &report.dangling {
+                    results.push(format!(
+                        "**^{}** in **{}** *(ID: {})*\n",
+                        reference.reference, reference.template, reference.substitute_id
+                    ));
+                }
+            }
+
+            if !report.cycles.is_empty() {
+                results.push("\n**Templates that reference each other in a loop:**\n".to_string());
+                for cycle in &report.cycles {
+                    results.push(format!("{}\n", cycle.join(", ")));
+                }
+            }
+
+            if !report.empty.is_empty() {
+                results
+                    .push("\n**Templates without substitutes that can be picked:**\n".to_string());
+                results.extend(report.empty.iter().map(|name| format!("{}\n", name)));
+            }
+
+            if !report.unreachable.is_empty() {
+                results.push("\n**Templates no other template references:**\n".to_string());
+                results.extend(report.unreachable.iter().map(|name| format!("{}\n", name)));
+            }
+
+            ctx.say_list(
+                &results.iter().map(|s| s.as_str()).collect::<Vec<&str>>()[..],
+                true,
+                None,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
 async fn say_list(
     ctx: Context<'_>,
     template: Option<String>,
     formatter: MessageListFormatter,
     show_ids: bool,
 ) -> Result<(), Error> {
+    let guild_id = ctx.template_guild_id();
     let db = ctx.data().funboy_db.lock().await;
 
     match template {
-        Some(tmp) => match db.get_sub_records(&tmp) {
+        Some(tmp) => match db.get_sub_records(guild_id, &tmp) {
             Ok(subs) => {
                 if subs.is_empty() {
                     ctx.say_ephemeral(
@@ -670,8 +1538,8 @@ async fn say_list(
                         .iter()
                         .map(|record| {
                             format!(
-                                "**ID:** {}\n**Substitute:**\n{}\n\n",
-                                record.id, record.name
+                                "**ID:** {}\n**Weight:** {}\n**Substitute:**\n{}\n\n",
+                                record.id, record.weight, record.name
                             )
                         })
                         .collect();
@@ -709,7 +1577,7 @@ async fn say_list(
                 .await?;
             }
         },
-        None => match db.get_templates() {
+        None => match db.get_templates(guild_id) {
             Ok(tmps) => {
                 if tmps.is_empty() {
                     ctx.say_ephemeral(ERROR_NO_TEMPLATES).await?;
@@ -791,22 +1659,70 @@ pub async fn list_numerically(ctx: Context<'_>, template: Option<String>) -> Res
 /// template name:
 /// **^verb^ing** which may be subsituted into **eating** if a substitute exists named **eat**
 ///
+/// Change a substitute by adding modifiers after the template name:
+/// **^fruit.cap** capitalizes, **^fruit.upper** and **^fruit.lower** change case, **^fruit.a** adds
+/// "a" or "an" and **^fruit.plural** pluralizes. Modifiers can be chained: **^fruit.plural.cap**
+///
+/// Pick between words without creating a template by separating them with **|** inside square
+/// brackets: **I love [apples|bananas|^fruit]**. Add weights to make some words more likely:
+/// **[common:3|rare:1]**
+///
+/// Reuse the same substitute by labeling a template with **#** and a label:
+/// **^name#1 went home and ^name#1 slept** uses the same name twice. Modifiers go after the
+/// label: **^name#1.cap**
+///
+/// To use a template character, square bracket or **|** as an ordinary character put a \\ in
+/// front of it: **I don\\'t like ^fruit** will never look up a template named **t** and
+/// **\\[a|b]** is output as **[a|b]**
+///
 /// You can also embed code to manipulate text using the FSL language. Type **/fsl_help** for more
 /// information.
 ///
+/// Set explain to **True** to privately see which substitute each template was replaced with and
+/// what each block of code evaluated to, nested under the template or code they came from.
+///
+/// Every output ends with the seed used to generate it. Generating the same text with the same
+/// seed gives the same output as long as the templates haven't changed since. Templates in deck
+/// mode depend on what was already drawn so they may still differ.
+///
 /// Example usage: **/generate I love ^fruit^s**
 /// Example output: **I love apples!**
 #[poise::command(slash_command, prefix_command, category = "Text substitution")]
-pub async fn generate(ctx: Context<'_>, text: String) -> Result<(), Error> {
+pub async fn generate(
+    ctx: Context<'_>,
+    text: String,
+    #[description = "Privately show which templates and code produced each part of the output"]
+    explain: Option<bool>,
+    #[description = "Reproduce an earlier output by generating with its seed"] seed: Option<u64>,
+) -> Result<(), Error> {
     ctx.defer().await?;
 
     let db_clone = ctx.data().funboy_db.clone();
-    let interpreted_prompt = tokio::task::spawn_blocking(move || interp_input(text, db_clone))
-        .await?
-        .await;
+    let guild_id = ctx.template_guild_id();
+    let channel_id = ctx.channel_id().get();
+    let user_id = ctx.author().id.get();
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen_range(0..=SEED_LIMIT));
+    let interpreted_prompt = interp_input_explained(
+        text,
+        db_clone,
+        guild_id,
+        channel_id,
+        user_id,
+        seed,
+        ctx.data().fsl_budget,
+    )
+    .await;
 
     match interpreted_prompt {
-        Ok(output) => ctx.say_long(&output, false).await?,
+        Ok((output, explanation)) => {
+            ctx.say_long(&format!("{}\n-# Seed: {}", output, seed), false)
+                .await?;
+
+            if explain.unwrap_or(false) {
+                ctx.say_long(&format_explanation(&explanation), true)
+                    .await?;
+            }
+        }
         Err(e) => {
             ctx.say_ephemeral(&format!("Error: {}", &e)).await?;
         }
@@ -814,3 +1730,56 @@ pub async fn generate(ctx: Context<'_>, text: String) -> Result<(), Error> {
 
     Ok(())
 }
+
+fn format_explanation_nodes(nodes: &[ExplanationNode], depth: usize, message: &mut String) {
+    for node in nodes {
+        let (line, children) = match node {
+            ExplanationNode::Template {
+                expansion,
+                children,
+            } => {
+                let source = match (expansion.bound, expansion.substitute_id) {
+                    (true, _) => " *(reused)*".to_string(),
+                    (false, Some(id)) => format!(" *(ID: {})*", id),
+                    (false, None) => String::new(),
+                };
+
+                let line = format!(
+                    "**^{}**{} → {}",
+                    expansion.template,
+                    source,
+                    ellipsize_if_long(&expansion.output, EXPLANATION_OUTPUT_LIMIT)
+                );
+                (line, children)
+            }
+            ExplanationNode::Code {
+                evaluation,
+                children,
+            } => {
+                let line = format!(
+                    "`{{{}}}` → {}",
+                    ellipsize_if_long(&evaluation.code, EXPLANATION_OUTPUT_LIMIT),
+                    ellipsize_if_long(&evaluation.output, EXPLANATION_OUTPUT_LIMIT)
+                );
+                (line, children)
+            }
+        };
+
+        message.push_str(&format!("{}- {}\n", "  ".repeat(depth), line));
+
+        format_explanation_nodes(children, depth + 1, message);
+    }
+}
+
+fn format_explanation(explanation: &Explanation) -> String {
+    let mut message = String::new();
+
+    if explanation.nodes.is_empty() {
+        message.push_str("No templates were substituted and no code was evaluated.\n");
+    } else {
+        message.push_str("**Templates and code:**\n");
+        format_explanation_nodes(&explanation.nodes, 0, &mut message);
+    }
+
+    message
+}
@@ -1,22 +1,34 @@
-use crate::text_interpolator::{defaults::TEMPLATE_CARROT, TextInterpolator};
+use crate::io_utils::input_interp::{seeded_rng, DatabaseSubstituteProvider, GenerationRng};
+use crate::storage::template_database::{GUILD_VARIABLE_USER_ID, LEGACY_GUILD_ID};
+use crate::text_interpolator::{defaults::TEMPLATE_CARROT, Expansion, TextInterpolator};
 use crate::FunboyDatabase;
 use async_recursion::async_recursion;
-use lexer::tokenize;
-use parser::{parse, Command, CommandType, ValueType};
+use error::FslError;
+use lexer::{tokenize, SourceSpan, Token, TokenType};
+use parser::{parse, Command, CommandType, ValueType, GET_SUB};
 use rand::{self, Rng};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
 use crate::io_utils::context_extension::MESSAGE_BYTE_LIMIT;
 
+mod error;
 #[allow(dead_code)]
 mod lexer;
 #[allow(dead_code)]
 mod parser;
 
 const LOOP_LIMIT: u16 = u16::MAX;
+/// Nested calls are boxed futures whose state lives on the heap, but polling them still nests
+/// stack frames for every call and keeps each caller's scope alive, so recursion stops early.
+const CALL_DEPTH_LIMIT: usize = 25;
 const VAR_MAP_BYTE_LIMIT: usize = 65535 * 100;
+/// Bytes of values each guild can store between generations.
+const STORED_VAR_BYTE_LIMIT: usize = 65535 * 10;
 const OUTPUT_BYTE_LIMIT: usize = MESSAGE_BYTE_LIMIT;
 
 const ERROR_NO_ARGS: &str = "takes no arguments";
@@ -43,6 +55,44 @@ const ERROR_ARG_ONE_MUST_NOT_BE_NONE: &str = "first argument must not be of type
 const ERROR_ARG_TWO_MUST_BE_IDENTIFIER: &str = "second argument must be of type Identifier";
 const ERROR_UNKNOWN_IDENTIFIER: &str = "no identifier exists named";
 const ERROR_ZERO_DIVISION: &str = "division by zero";
+const ERROR_UNKNOWN_FUNCTION: &str = "no command or function exists named";
+const ERROR_ARG_ONE_MUST_BE_IDENTIFIER: &str = "first argument must be of type Identifier";
+const ERROR_NO_DATABASE: &str = "interpreter attempt to use database with no reference.";
+const ERROR_BUDGET_EXCEEDED: &str = "execution budget exceeded";
+const ERROR_KEYS_MUST_BE_TEXT: &str = "keys must be of type Text";
+const ERROR_ARGS_MUST_BE_KEY_AND_MAP: &str = "key and map arguments must be of type Text and Map";
+const ERROR_UNKNOWN_KEY: &str = "no key exists named";
+
+/// Finds every `get_sub("template")` call in the code, returning each template along with the
+/// byte range of its name.
+///
+/// Only calls given a single piece of text are found, calls with an argument built by another
+/// command can't be known without running the code.
+pub fn find_sub_calls(code: &str) -> Vec<(String, Range<usize>)> {
+    let tokens: Vec<Token> = tokenize(code)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|token| !(token.token_type == TokenType::Identifier && token.value.is_empty()))
+        .collect();
+
+    tokens
+        .windows(6)
+        .filter_map(|window| match window {
+            [command, opening_parenthesis, opening_quote, text, closing_quote, closing_parenthesis]
+                if command.token_type == TokenType::Command
+                    && command.value == GET_SUB
+                    && opening_parenthesis.token_type == TokenType::OpeningParenthesis
+                    && opening_quote.token_type == TokenType::OpeningQuote
+                    && text.token_type == TokenType::Text
+                    && closing_quote.token_type == TokenType::ClosingQuote
+                    && closing_parenthesis.token_type == TokenType::ClosingParenthesis =>
+            {
+                Some((text.value.clone(), text.span.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
 
 #[derive(Debug)]
 pub struct VarMap {
@@ -75,14 +125,71 @@ impl VarMap {
     }
 }
 
+/// Limits that stop long running code from stalling generation. The budget is shared by every
+/// block of code in a call to [`Interpreter::interpret_embedded_code`].
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutionBudget {
+    /// How many commands may be evaluated, counting every command nested in loops and functions.
+    pub max_steps: u64,
+    /// How long code may run for.
+    pub max_duration: Duration,
+}
+
+impl Default for ExecutionBudget {
+    fn default() -> Self {
+        ExecutionBudget {
+            max_steps: 250_000,
+            max_duration: Duration::from_secs(2),
+        }
+    }
+}
+
+/// A function defined with def.
+#[derive(Debug, Clone)]
+struct Function {
+    params: Vec<String>,
+    body: Command,
+    /// The block of code the function was defined in, which the body's location refers to.
+    code_block: usize,
+}
+
 #[derive(Debug)]
 pub struct Interpreter {
     vars: VarMap,
+    /// Variables of each function being called, innermost last.
+    locals: Vec<VarMap>,
+    functions: HashMap<String, Function>,
+    /// Number of blocks of code interpreted so far.
+    code_block: usize,
+    budget: ExecutionBudget,
+    /// Commands evaluated against the budget so far.
+    steps: u64,
+    /// When the first command was evaluated against the budget.
+    started: Option<Instant>,
     copy_buffer: ValueType,
     output: String,
     log: Vec<ValueType>,
     db: Option<Arc<Mutex<FunboyDatabase>>>,
+    guild_id: u64,
+    channel_id: u64,
+    /// User whose values are read and written by store and load when asked for.
+    user_id: u64,
+    rng: GenerationRng,
     interpolator: TextInterpolator,
+    /// Templates substituted by get_sub in the block of code being evaluated.
+    expansions: Vec<Expansion>,
+    evaluated_code: Vec<CodeEvaluation>,
+}
+
+/// A block of embedded code and the text it evaluated to.
+#[derive(Debug, Clone)]
+pub struct CodeEvaluation {
+    pub code: String,
+    pub output: String,
+    /// Byte range of the block, braces included, in the text it was embedded in.
+    pub span: Range<usize>,
+    /// Templates substituted by get_sub while evaluating the block.
+    pub expansions: Vec<Expansion>,
 }
 
 impl Interpreter {
@@ -90,46 +197,105 @@ impl Interpreter {
         Self {
             copy_buffer: ValueType::None,
             vars: VarMap::new(),
+            locals: Vec::new(),
+            functions: HashMap::new(),
+            code_block: 0,
+            budget: ExecutionBudget::default(),
+            steps: 0,
+            started: None,
             output: String::new(),
             log: Vec::new(),
             interpolator: TextInterpolator::default(),
+            expansions: Vec::new(),
+            evaluated_code: Vec::new(),
             db: None,
+            guild_id: LEGACY_GUILD_ID,
+            channel_id: 0,
+            user_id: GUILD_VARIABLE_USER_ID,
+            rng: seeded_rng(rand::random()),
         }
     }
 
-    pub fn new_with_db(db: Arc<Mutex<FunboyDatabase>>) -> Self {
+    /// Creates an interpreter that can read the guild's templates and the values stored by the
+    /// guild and user. Random commands use the given random number generator so they can be
+    /// reproduced, and code stops once it has spent the budget.
+    pub fn new_with_db(
+        db: Arc<Mutex<FunboyDatabase>>,
+        guild_id: u64,
+        channel_id: u64,
+        user_id: u64,
+        rng: GenerationRng,
+        budget: ExecutionBudget,
+    ) -> Self {
         Self {
             copy_buffer: ValueType::None,
             vars: VarMap::new(),
+            locals: Vec::new(),
+            functions: HashMap::new(),
+            code_block: 0,
+            budget,
+            steps: 0,
+            started: None,
             output: String::new(),
             log: Vec::new(),
             interpolator: TextInterpolator::default(),
+            expansions: Vec::new(),
+            evaluated_code: Vec::new(),
             db: Some(db),
+            guild_id,
+            channel_id,
+            user_id,
+            rng,
         }
     }
 
+    /// Substitutes templates read by get_sub with the interpolator that substituted the templates
+    /// of the same generation, so labeled templates resolve to the substitutes already chosen.
+    pub fn set_interpolator(&mut self, interpolator: TextInterpolator) {
+        self.interpolator = interpolator;
+    }
+
+    /// Every block of embedded code evaluated so far, in the order they finished evaluating.
+    pub fn get_evaluated_code(&self) -> &[CodeEvaluation] {
+        &self.evaluated_code
+    }
+
     pub async fn interpret_embedded_code(&mut self, input: &str) -> Result<String, String> {
+        self.steps = 0;
+        self.started = None;
+        self.expansions.clear();
+
         let mut output = String::with_capacity(input.len());
-        let mut code_stack: Vec<String> = Vec::new();
+        // Code being read along with the byte index of its opening brace
+        let mut code_stack: Vec<(usize, String)> = Vec::new();
 
         let mut code_depth: i16 = 0;
 
-        for c in input.chars() {
+        for (i, c) in input.char_indices() {
             if c == '{' {
-                code_stack.push(String::new());
+                code_stack.push((i, String::new()));
                 code_depth += 1;
             } else if c == '}' {
                 code_depth -= 1;
                 if code_depth < 0 {
-                    return Err("Unmatched curly braces".to_string());
+                    return Err(unmatched_brace(input, i));
                 } else {
                     match code_stack.pop() {
-                        Some(code) => {
+                        Some((start, code)) => {
                             match self.interpret(&code).await {
-                                Ok(eval) => match code_stack.last_mut() {
-                                    Some(code) => code.push_str(&eval),
-                                    None => output.push_str(&eval),
-                                },
+                                Ok(eval) => {
+                                    self.evaluated_code.push(CodeEvaluation {
+                                        code,
+                                        output: eval.clone(),
+                                        span: start..i + c.len_utf8(),
+                                        expansions: self.expansions.drain(..).collect(),
+                                    });
+
+                                    match code_stack.last_mut() {
+                                        Some((_, code)) => code.push_str(&eval),
+                                        None => output.push_str(&eval),
+                                    }
+                                }
                                 Err(e) => return Err(e),
                             };
                         }
@@ -140,21 +306,29 @@ impl Interpreter {
                 output.push(c);
             } else {
                 match code_stack.last_mut() {
-                    Some(s) => s.push(c),
+                    Some((_, s)) => s.push(c),
                     None => {}
                 }
             }
         }
 
-        if code_depth != 0 {
-            return Err("Unmatched curly braces".to_string());
+        if let Some((i, _)) = code_stack.first() {
+            return Err(unmatched_brace(input, *i));
         }
 
         Ok(output)
     }
 
+    /// Evaluates the code, describing any error with the code it happened in.
     pub async fn interpret(&mut self, code: &str) -> Result<String, String> {
-        let commands = parse(tokenize(code))?;
+        self.interpret_commands(code)
+            .await
+            .map_err(|error| error.render(code))
+    }
+
+    async fn interpret_commands(&mut self, code: &str) -> Result<String, FslError> {
+        self.code_block += 1;
+        let commands = parse(tokenize(code)?)?;
 
         let mut final_value = ValueType::None;
 
@@ -164,6 +338,7 @@ impl Interpreter {
 
         match final_value {
             ValueType::List(_) => {}
+            ValueType::Map(_) => {}
             ValueType::Identifier(_) => {}
             ValueType::Command(_) => {}
             ValueType::None => {}
@@ -174,21 +349,88 @@ impl Interpreter {
     }
 
     pub async fn interpret_and_log(&mut self, code: &str) -> Result<String, String> {
-        let commands = parse(tokenize(code))?;
+        let render = |error: FslError| error.render(code);
+        self.code_block += 1;
+        let commands = parse(tokenize(code).map_err(render)?).map_err(render)?;
 
         for command in commands {
-            let value = self.eval_command(command).await?;
+            let value = self.eval_command(command).await.map_err(render)?;
             self.log.push(value);
         }
 
         Ok(self.output.drain(..).collect())
     }
 
+    /// Variables of the function being called, or the global variables outside of a function.
+    fn scope(&mut self) -> &mut VarMap {
+        self.locals.last_mut().unwrap_or(&mut self.vars)
+    }
+
+    /// Finds a variable of the function being called before falling back to the global variables.
+    fn get_var(&mut self, name: &String) -> Option<&mut ValueType> {
+        if let Some(scope) = self.locals.last_mut() {
+            if scope.data.contains_key(name) {
+                return scope.get_var(name);
+            }
+        }
+
+        self.vars.get_var(name)
+    }
+
+    /// The user whose stored values are used if the optional argument is true, otherwise the
+    /// values shared by the guild are used.
+    fn variable_owner(
+        &self,
+        per_user: Option<&ValueType>,
+        command_type: &CommandType,
+    ) -> Result<u64, FslError> {
+        match per_user {
+            None | Some(ValueType::Bool(false)) => Ok(GUILD_VARIABLE_USER_ID),
+            Some(ValueType::Bool(true)) => Ok(self.user_id),
+            Some(_) => Err(command_type.gen_err("third argument must be of type Bool")),
+        }
+    }
+
+    /// Counts a command against the execution budget, failing once the budget is spent.
+    fn spend_step(&mut self, command_type: &CommandType) -> Result<(), FslError> {
+        self.steps = self.steps.saturating_add(1);
+        let started = *self.started.get_or_insert_with(Instant::now);
+
+        if self.steps > self.budget.max_steps {
+            Err(command_type.gen_err(&format!(
+                "{}, no more than {} commands may be evaluated",
+                ERROR_BUDGET_EXCEEDED, self.budget.max_steps
+            )))
+        } else if started.elapsed() > self.budget.max_duration {
+            Err(command_type.gen_err(&format!(
+                "{}, code may not run for longer than {} ms",
+                ERROR_BUDGET_EXCEEDED,
+                self.budget.max_duration.as_millis()
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Evaluates the command, attributing any error to it unless a nested command caused it.
+    async fn eval_command(&mut self, command: Command) -> Result<ValueType, FslError> {
+        let command_type = command.command_type.clone();
+        let location = command.location;
+
+        let output = match self.spend_step(&command_type) {
+            Ok(()) => self.run_command(command).await,
+            Err(error) => Err(error),
+        };
+
+        output.map_err(|error| error.within(command_type.to_str(), location))
+    }
+
     #[async_recursion]
-    async fn eval_command(&mut self, command: Command) -> Result<ValueType, String> {
+    async fn run_command(&mut self, command: Command) -> Result<ValueType, FslError> {
         let mut args: Vec<ValueType> = Vec::new();
         let mut has_float_arg = false;
         let mut i = 0;
+        let arg_count = command.args.len();
 
         for arg in command.args {
             match arg {
@@ -198,6 +440,7 @@ impl Interpreter {
                         CommandType::IfThenElse if i == 1 || i == 2 => args.push(arg),
                         CommandType::Repeat if i != 0 => args.push(arg),
                         CommandType::While => args.push(arg),
+                        CommandType::Def if i == arg_count - 1 => args.push(arg),
                         _ => args.push(self.eval_command(sub_command.clone()).await?),
                     };
                 }
@@ -211,6 +454,7 @@ impl Interpreter {
                 ValueType::None => args.push(arg),
                 ValueType::Bool(_) => args.push(arg),
                 ValueType::List(_) => args.push(arg),
+                ValueType::Map(_) => args.push(arg),
             }
             i += 1;
         }
@@ -366,7 +610,7 @@ impl Interpreter {
                 if args.len() < 2 {
                     return Err(command_type.gen_err(ERROR_TWO_OR_MORE_ARGS));
                 } else {
-                    let mut rng = rand::thread_rng();
+                    let mut rng = self.rng.lock().unwrap_or_else(PoisonError::into_inner);
                     let index = rng.gen_range(0..args.len());
                     Ok(args[index].clone())
                 }
@@ -375,7 +619,7 @@ impl Interpreter {
                 if args.len() != 2 {
                     return Err(command_type.gen_err(ERROR_EXACTLY_TWO_ARGS));
                 } else {
-                    let mut rng = rand::thread_rng();
+                    let mut rng = self.rng.lock().unwrap_or_else(PoisonError::into_inner);
                     match &args[0] {
                         ValueType::Int(min) => match &args[1] {
                             ValueType::Int(max) => Ok(ValueType::Int(rng.gen_range(*min..=*max))),
@@ -495,12 +739,12 @@ impl Interpreter {
                             }
                             _ => {
                                 match self
-                                    .vars
+                                    .scope()
                                     .insert_var(identifier.to_string(), args[0].clone())
                                 {
                                     Ok(_) => return Ok(ValueType::None),
                                     Err(e) => {
-                                        return Err(e);
+                                        return Err(e.into());
                                     }
                                 }
                             }
@@ -524,12 +768,12 @@ impl Interpreter {
                         };
                     }
                     match self
-                        .vars
+                        .scope()
                         .insert_var(identifier.to_string(), ValueType::List(list))
                     {
                         Ok(_) => return Ok(ValueType::None),
                         Err(e) => {
-                            return Err(e);
+                            return Err(e.into());
                         }
                     }
                 } else {
@@ -544,7 +788,7 @@ impl Interpreter {
                     Ok(self.copy_buffer.clone())
                 } else {
                     match &args[0] {
-                        ValueType::Identifier(identifier) => match self.vars.get_var(identifier) {
+                        ValueType::Identifier(identifier) => match self.get_var(identifier) {
                             Some(value) => Ok(value.clone()),
                             None => Err(command_type.gen_err(&format!(
                                 "{} **{}**",
@@ -563,10 +807,10 @@ impl Interpreter {
                     {
                         self.output.push_str(&arg_string);
                     } else {
-                        return Err(format!(
+                        return Err(command_type.gen_err(&format!(
                             "Output byte limit of {} bytes exceeded",
                             OUTPUT_BYTE_LIMIT
-                        ));
+                        )));
                     }
                 }
 
@@ -782,26 +1026,31 @@ impl Interpreter {
                     match &args[0] {
                         ValueType::Text(sub) => match self.db.clone() {
                             Some(fdb) => {
-                                let fdb = fdb.lock().await;
-                                let output = self.interpolator.interp(
-                                    &(TEMPLATE_CARROT.to_string() + sub),
-                                    &|template| match fdb.get_random_subs(template) {
-                                        Ok(sub) => Some(sub),
-                                        Err(_) => None,
-                                    },
+                                let provider = DatabaseSubstituteProvider::new(
+                                    fdb,
+                                    self.guild_id,
+                                    self.channel_id,
+                                    self.rng.clone(),
                                 );
-                                drop(fdb);
+                                let output = self
+                                    .interpolator
+                                    .continue_interpolation(
+                                        &(TEMPLATE_CARROT.to_string() + sub),
+                                        &provider,
+                                    )
+                                    .await;
 
                                 match output {
-                                    Ok(o) => Ok(ValueType::Text(o)),
-                                    Err(e) => Err(e.to_string()),
+                                    Ok(o) => {
+                                        self.expansions.extend(o.expansions);
+                                        Ok(ValueType::Text(o.text))
+                                    }
+                                    Err(e) => Err(command_type.gen_err(&e.to_string())),
                                 }
                             }
                             None => {
-                                let error =
-                                    "interpreter attempt to use database with no reference.";
-                                eprintln!("Error: {}", error);
-                                Err(error.to_string())
+                                eprintln!("Error: {}", ERROR_NO_DATABASE);
+                                Err(command_type.gen_err(ERROR_NO_DATABASE))
                             }
                         },
                         _ => Err(command_type.gen_err(ERROR_ARG_MUST_BE_TEXT)),
@@ -1147,15 +1396,289 @@ impl Interpreter {
                     }
                 }
             }
+            CommandType::Def => {
+                let Some((ValueType::Command(body), names)) = args.split_last() else {
+                    return Err(command_type.gen_err("last argument must be of type Command"));
+                };
+
+                let mut names = names.iter().map(|name| match name {
+                    ValueType::Identifier(name) => Ok(name.clone()),
+                    _ => Err(command_type
+                        .gen_err("arguments before the last must be of type Identifier")),
+                });
+
+                let Some(name) = names.next() else {
+                    return Err(command_type.gen_err(ERROR_TWO_OR_MORE_ARGS));
+                };
+                let name = name?;
+                let params = names.collect::<Result<Vec<String>, FslError>>()?;
+
+                if CommandType::from_str(&name).is_ok() {
+                    return Err(command_type.gen_err(&format!(
+                        "cannot redefine the built in command **{}**",
+                        name
+                    )));
+                } else if params.iter().collect::<HashSet<_>>().len() != params.len() {
+                    return Err(command_type.gen_err("parameter names must be unique"));
+                }
+
+                self.functions.insert(
+                    name,
+                    Function {
+                        params,
+                        body: body.clone(),
+                        code_block: self.code_block,
+                    },
+                );
+
+                Ok(ValueType::None)
+            }
+            CommandType::Store => {
+                if args.len() != 2 && args.len() != 3 {
+                    return Err(command_type.gen_err("must have two or three arguments"));
+                }
+
+                let ValueType::Identifier(name) = &args[0] else {
+                    return Err(command_type.gen_err(ERROR_ARG_ONE_MUST_BE_IDENTIFIER));
+                };
+                let Some(value) = args[1].to_json() else {
+                    return Err(command_type.gen_err(
+                        "second argument must be of type Int, Float, Text, Bool, List or Map",
+                    ));
+                };
+                let user_id = self.variable_owner(args.get(2), &command_type)?;
+                let Some(db) = self.db.clone() else {
+                    return Err(command_type.gen_err(ERROR_NO_DATABASE));
+                };
+
+                let size = args[1].get_size().saturating_add(name.len());
+                let stored = db.lock().await.store_variable(
+                    self.guild_id,
+                    user_id,
+                    name,
+                    &value.to_string(),
+                    size,
+                    STORED_VAR_BYTE_LIMIT,
+                );
+
+                match stored {
+                    Ok(true) => Ok(ValueType::None),
+                    Ok(false) => Err(command_type.gen_err(&format!(
+                        "stored values must not exceed {} bytes per server",
+                        STORED_VAR_BYTE_LIMIT
+                    ))),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        Err(command_type.gen_err("value could not be stored"))
+                    }
+                }
+            }
+            CommandType::Load => {
+                if args.is_empty() || args.len() > 3 {
+                    return Err(command_type.gen_err("must have one to three arguments"));
+                }
+
+                let ValueType::Identifier(name) = &args[0] else {
+                    return Err(command_type.gen_err(ERROR_ARG_ONE_MUST_BE_IDENTIFIER));
+                };
+                let user_id = self.variable_owner(args.get(2), &command_type)?;
+                let Some(db) = self.db.clone() else {
+                    return Err(command_type.gen_err(ERROR_NO_DATABASE));
+                };
+
+                let loaded = db.lock().await.load_variable(self.guild_id, user_id, name);
+
+                match loaded {
+                    Ok(Some(value)) => serde_json::from_str(&value)
+                        .ok()
+                        .as_ref()
+                        .and_then(ValueType::from_json)
+                        .ok_or_else(|| command_type.gen_err("stored value could not be read")),
+                    Ok(None) => {
+                        match args.get(1) {
+                            Some(default) => Ok(default.clone()),
+                            None => Err(command_type
+                                .gen_err(&format!("no value is stored named **{}**", name))),
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        Err(command_type.gen_err("value could not be loaded"))
+                    }
+                }
+            }
+            CommandType::Map => {
+                if args.len() % 2 != 0 {
+                    return Err(command_type.gen_err("must have a value for every key"));
+                }
+
+                let mut map = BTreeMap::new();
+                for pair in args.chunks(2) {
+                    let ValueType::Text(key) = &pair[0] else {
+                        return Err(command_type.gen_err(ERROR_KEYS_MUST_BE_TEXT));
+                    };
+                    map.insert(key.clone(), map_value(&command_type, &pair[1])?);
+                }
+
+                Ok(ValueType::Map(map))
+            }
+            CommandType::MapGet => {
+                if args.len() != 2 {
+                    return Err(command_type.gen_err(ERROR_EXACTLY_TWO_ARGS));
+                }
+
+                match (&args[0], &args[1]) {
+                    (ValueType::Text(key), ValueType::Map(map)) => match map.get(key) {
+                        Some(value) => Ok(value.clone()),
+                        None => {
+                            Err(command_type.gen_err(&format!("{} **{}**", ERROR_UNKNOWN_KEY, key)))
+                        }
+                    },
+                    _ => Err(command_type.gen_err(ERROR_ARGS_MUST_BE_KEY_AND_MAP)),
+                }
+            }
+            CommandType::MapSet => {
+                if args.len() != 3 {
+                    return Err(command_type.gen_err(ERROR_EXACTLY_THREE_ARGS));
+                }
+
+                match (&args[1], &args[2]) {
+                    (ValueType::Text(key), ValueType::Map(map)) => {
+                        let mut map = map.clone();
+                        map.insert(key.clone(), map_value(&command_type, &args[0])?);
+                        Ok(ValueType::Map(map))
+                    }
+                    _ => Err(command_type.gen_err(ERROR_ARGS_MUST_BE_KEY_AND_MAP)),
+                }
+            }
+            CommandType::MapRemove => {
+                if args.len() != 2 {
+                    return Err(command_type.gen_err(ERROR_EXACTLY_TWO_ARGS));
+                }
+
+                match (&args[0], &args[1]) {
+                    (ValueType::Text(key), ValueType::Map(map)) => {
+                        let mut map = map.clone();
+                        match map.remove(key) {
+                            Some(_) => Ok(ValueType::Map(map)),
+                            None => {
+                                Err(command_type
+                                    .gen_err(&format!("{} **{}**", ERROR_UNKNOWN_KEY, key)))
+                            }
+                        }
+                    }
+                    _ => Err(command_type.gen_err(ERROR_ARGS_MUST_BE_KEY_AND_MAP)),
+                }
+            }
+            CommandType::MapKeys => {
+                if args.len() != 1 {
+                    return Err(command_type.gen_err(ERROR_EXACTLY_ONE_ARG));
+                }
+
+                match &args[0] {
+                    ValueType::Map(map) => Ok(ValueType::List(
+                        map.keys().cloned().map(ValueType::Text).collect(),
+                    )),
+                    _ => Err(command_type.gen_err("argument must be of type Map")),
+                }
+            }
+            CommandType::MapHas => {
+                if args.len() != 2 {
+                    return Err(command_type.gen_err(ERROR_EXACTLY_TWO_ARGS));
+                }
+
+                match (&args[0], &args[1]) {
+                    (ValueType::Text(key), ValueType::Map(map)) => {
+                        Ok(ValueType::Bool(map.contains_key(key)))
+                    }
+                    _ => Err(command_type.gen_err(ERROR_ARGS_MUST_BE_KEY_AND_MAP)),
+                }
+            }
+            CommandType::Call(ref name) => {
+                let Some(function) = self.functions.get(name).cloned() else {
+                    return Err(
+                        command_type.gen_err(&format!("{} **{}**", ERROR_UNKNOWN_FUNCTION, name))
+                    );
+                };
+
+                if args.len() != function.params.len() {
+                    return Err(command_type.gen_err(&format!(
+                        "takes {} arguments but was given {}",
+                        function.params.len(),
+                        args.len()
+                    )));
+                } else if self.locals.len() >= CALL_DEPTH_LIMIT {
+                    return Err(command_type.gen_err(&format!(
+                        "function calls must not be nested more than {} deep",
+                        CALL_DEPTH_LIMIT
+                    )));
+                }
+
+                let mut scope = VarMap::new();
+                for (param, arg) in function.params.into_iter().zip(args) {
+                    scope.insert_var(param, arg)?;
+                }
+
+                self.locals.push(scope);
+                let output = self.eval_command(function.body).await;
+                self.locals.pop();
+
+                // The body can only be pointed at within the code it was defined in
+                match function.code_block == self.code_block {
+                    true => output,
+                    false => output.map_err(FslError::unlocated),
+                }
+            }
         }
     }
 }
 
+/// Checks that the value can be kept in a Map.
+fn map_value(command_type: &CommandType, value: &ValueType) -> Result<ValueType, FslError> {
+    match value {
+        ValueType::Identifier(_) => {
+            Err(command_type.gen_err("cannot store values of type Identifier in type Map"))
+        }
+        ValueType::None => {
+            Err(command_type.gen_err("cannot store values of type None in type Map"))
+        }
+        _ => Ok(value.clone()),
+    }
+}
+
+/// Describes a curly brace without a matching brace, pointing at it in the input.
+fn unmatched_brace(input: &str, i: usize) -> String {
+    FslError::syntax(
+        "Unmatched curly braces",
+        SourceSpan::new(input, &(i..i + 1)),
+    )
+    .render(input)
+}
+
 #[cfg(test)]
 mod tests {
 
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use tokio::sync::Mutex;
+
     use crate::fsl_documentation::get_command_documentation;
-    use crate::fsl_interpreter::Interpreter;
+    use crate::fsl_interpreter::{find_sub_calls, ExecutionBudget, Interpreter};
+    use crate::io_utils::input_interp::seeded_rng;
+    use crate::FunboyDatabase;
+
+    #[test]
+    fn find_get_sub_calls() {
+        let code = "add(get_sub(\"noun\"), get_sub ( \"verb\" )) get_sub(concat(\"a\", \"b\"))";
+        let calls = find_sub_calls(code);
+
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].0, "noun");
+        assert_eq!(&code[calls[0].1.clone()], "noun");
+        assert_eq!(calls[1].0, "verb");
+        assert_eq!(&code[calls[1].1.clone()], "verb");
+    }
 
     #[tokio::test]
     async fn validate_documentation_examples() {
@@ -1180,6 +1703,188 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn locate_failing_command() {
+        let mut interpreter = Interpreter::new();
+        let error = interpreter
+            .interpret("print(add(1, 2), add(3, \"a\"))")
+            .await
+            .unwrap_err();
+
+        assert!(error.starts_with("Semantic error on line 1, column 18\n"));
+        assert!(error.contains("\n                 ^^^^^^^^^^^\n"));
+
+        let error = interpreter
+            .interpret_embedded_code("text {print(\"a\")")
+            .await
+            .unwrap_err();
+        assert!(error.starts_with("Syntax error on line 1, column 6\n"));
+    }
+
+    #[tokio::test]
+    async fn user_defined_functions() {
+        let mut interpreter = Interpreter::new();
+        let output = interpreter
+            .interpret_embedded_code(
+                "{copy(1, x) def(shadow, x, concat(copy(2, y), paste(x), paste(y)))}\
+                 {print(shadow(\"a\"), paste(x))}",
+            )
+            .await
+            .unwrap();
+        assert_eq!(output, "a21");
+
+        let error = interpreter.interpret("paste(y)").await.unwrap_err();
+        assert!(error.contains("no identifier exists named **y**"));
+
+        let error = interpreter.interpret("shadow(1, 2)").await.unwrap_err();
+        assert!(error.contains("takes 1 arguments but was given 2"));
+
+        let error = interpreter
+            .interpret("def(forever, forever()) forever()")
+            .await
+            .unwrap_err();
+        assert!(error.contains("must not be nested more than"));
+
+        let error = interpreter.interpret("undefined(1)").await.unwrap_err();
+        assert!(error.contains("no command or function exists named **undefined**"));
+
+        // Unknown names are parsed as calls, so misspelled commands fail once they are evaluated
+        let error = interpreter
+            .interpret("print(1) ad(1, 2)")
+            .await
+            .unwrap_err();
+        assert!(error.contains("no command or function exists named **ad**"));
+        assert!(error.contains("column 10"));
+
+        let error = interpreter.interpret("def(add, 1)").await.unwrap_err();
+        assert!(error.contains("last argument must be of type Command"));
+    }
+
+    #[tokio::test]
+    async fn locate_errors_in_functions() {
+        let mut interpreter = Interpreter::new();
+        let error = interpreter
+            .interpret("def(half, n, div(paste(n), 0)) print(half(1))")
+            .await
+            .unwrap_err();
+        assert!(error.starts_with("Semantic error on line 1, column 14\n"));
+
+        let error = interpreter.interpret("print(half(1))").await.unwrap_err();
+        assert!(error.starts_with("Semantic error on line 1, column 7\n"));
+        assert!(error.contains("Command: half\nDescription: div: division by zero"));
+    }
+
+    #[tokio::test]
+    async fn store_values_between_generations() {
+        let db = FunboyDatabase::from_path("test24.db").unwrap();
+        db.clear().unwrap();
+        let db = Arc::new(Mutex::new(db));
+        let interpreter = |user_id| {
+            Interpreter::new_with_db(
+                db.clone(),
+                1,
+                1,
+                user_id,
+                seeded_rng(0),
+                ExecutionBudget::default(),
+            )
+        };
+
+        let code = "store(count, add(load(count, 0), 1)) print(load(count))";
+        assert_eq!(interpreter(1).interpret(code).await.unwrap(), "1");
+        assert_eq!(interpreter(2).interpret(code).await.unwrap(), "2");
+
+        let code = "copy(1, 2.5, \"a\", true, l) store(mine, paste(l), true) \
+                    print(load(mine, 0, true), load(mine, 0))";
+        assert_eq!(
+            interpreter(1).interpret(code).await.unwrap(),
+            "[1, 2.5, a, true]0"
+        );
+        assert_eq!(
+            interpreter(2)
+                .interpret("print(load(mine, 0, true))")
+                .await
+                .unwrap(),
+            "0"
+        );
+
+        let error = interpreter(1).interpret("store(x, y)").await.unwrap_err();
+        assert!(error.contains("second argument must be of type"));
+
+        let error = interpreter(1).interpret("load(missing)").await.unwrap_err();
+        assert!(error.contains("no value is stored named **missing**"));
+    }
+
+    #[tokio::test]
+    async fn nested_loops_exceed_budget() {
+        let mut interpreter = Interpreter::new();
+        let started = Instant::now();
+        let error = interpreter
+            .interpret_embedded_code("{repeat(65535, repeat(65535, add(1, 1)))}")
+            .await
+            .unwrap_err();
+
+        assert!(error.contains("execution budget exceeded, no more than 250000 commands"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        interpreter.budget = ExecutionBudget {
+            max_steps: 2,
+            max_duration: Duration::from_secs(60),
+        };
+        assert!(interpreter
+            .interpret_embedded_code("{print(add(1, 2))}")
+            .await
+            .is_ok());
+        assert!(interpreter
+            .interpret_embedded_code("{print(add(1, 2), add(3, 4))}")
+            .await
+            .is_err());
+
+        interpreter.budget.max_duration = Duration::ZERO;
+        let error = interpreter
+            .interpret_embedded_code("{repeat(65535, add(1, 1))}")
+            .await
+            .unwrap_err();
+        assert!(error.contains("code may not run for longer than 0 ms"));
+    }
+
+    #[tokio::test]
+    async fn map_commands() {
+        let mut interpreter = Interpreter::new();
+        let output = interpreter
+            .interpret(
+                "copy(map(\"they\", map(\"object\", \"them\")), pronouns) \
+                 print(map_get(\"object\", map_get(\"they\", paste(pronouns))), \" \", \
+                 map_has(\"he\", paste(pronouns)), \" \", map(), \" \", paste(pronouns))",
+            )
+            .await
+            .unwrap();
+        assert_eq!(output, "them false {} {they: {object: them}}");
+
+        let output = interpreter
+            .interpret(
+                "copy(map_set(\"she\", \"subject\", \
+                 map_set(\"her\", \"object\", map_get(\"they\", paste(pronouns)))), she) \
+                 print(map_keys(paste(she)), \" \", map_get(\"object\", paste(she)), \" \", \
+                 map_remove(\"object\", paste(she)))",
+            )
+            .await
+            .unwrap();
+        assert_eq!(output, "[object, subject] her {subject: she}");
+
+        let error = interpreter.interpret("map(\"a\")").await.unwrap_err();
+        assert!(error.contains("must have a value for every key"));
+
+        let error = interpreter.interpret("map(1, 2)").await.unwrap_err();
+        assert!(error.contains("keys must be of type Text"));
+
+        let error = interpreter
+            .interpret("map_remove(\"b\", map(\"a\", 1))")
+            .await
+            .unwrap_err();
+        assert!(error.contains("no key exists named **b**"));
+    }
+
     #[tokio::test]
     async fn validate_random_range_command() {
         let mut interpreter = Interpreter::new();
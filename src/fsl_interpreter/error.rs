@@ -0,0 +1,211 @@
+use std::fmt;
+
+use super::lexer::SourceSpan;
+
+/// Characters of the line shown on either side of the underlined code.
+const SNIPPET_CONTEXT: usize = 40;
+/// Longest underline shown, longer spans are cut off.
+const UNDERLINE_LIMIT: usize = 80;
+const ELLIPSIS: &str = "...";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The code couldn't be read.
+    Syntax,
+    /// A command couldn't be evaluated.
+    Semantic,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Syntax => write!(f, "Syntax"),
+            ErrorKind::Semantic => write!(f, "Semantic"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FslError {
+    pub kind: ErrorKind,
+    /// Name of the command being evaluated when the error happened.
+    pub command: Option<String>,
+    pub description: String,
+    /// Where the error happened in the code, None until it's known.
+    pub location: Option<SourceSpan>,
+}
+
+impl FslError {
+    pub fn syntax(description: &str, location: SourceSpan) -> FslError {
+        FslError {
+            kind: ErrorKind::Syntax,
+            command: None,
+            description: description.to_string(),
+            location: Some(location),
+        }
+    }
+
+    pub fn semantic(command: &str, description: &str) -> FslError {
+        FslError {
+            kind: ErrorKind::Semantic,
+            command: Some(command.to_string()),
+            description: description.to_string(),
+            location: None,
+        }
+    }
+
+    /// Attributes the error to a command unless it was already attributed to one nested inside.
+    pub fn within(mut self, command: &str, location: SourceSpan) -> FslError {
+        if self.location.is_none() {
+            self.command.get_or_insert_with(|| command.to_string());
+            self.location = Some(location);
+        }
+
+        self
+    }
+
+    /// Forgets where the error happened so it can be attributed to the command that led to it,
+    /// keeping the failed command's name in the description.
+    pub fn unlocated(self) -> FslError {
+        let description = match self.command {
+            Some(command) => format!("{}: {}", command, self.description),
+            None => self.description,
+        };
+
+        FslError {
+            kind: self.kind,
+            command: None,
+            description,
+            location: None,
+        }
+    }
+
+    /// Describes the error with the line of code it happened on, underlining where it happened.
+    pub fn render(&self, code: &str) -> String {
+        let mut err = self.kind.to_string();
+
+        if let Some(location) = &self.location {
+            err.push_str(&format!(
+                " error on line {}, column {}\n```\n{}\n```",
+                location.start.line,
+                location.start.column,
+                underline(code, location)
+            ));
+        }
+
+        if let Some(command) = &self.command {
+            err.push_str(&format!("\nCommand: {}", command));
+        }
+
+        err.push_str(&format!("\nDescription: {}", self.description));
+
+        err
+    }
+}
+
+impl From<String> for FslError {
+    fn from(description: String) -> FslError {
+        FslError {
+            kind: ErrorKind::Semantic,
+            command: None,
+            description,
+            location: None,
+        }
+    }
+}
+
+/// Returns the line the span starts on with carets underneath the span, shortening long lines to
+/// the code around it.
+fn underline(code: &str, location: &SourceSpan) -> String {
+    let line: Vec<char> = code
+        .lines()
+        .nth(location.start.line - 1)
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .collect();
+
+    let start = (location.start.column - 1).min(line.len());
+    let end = match location.end.line == location.start.line {
+        true => (location.end.column - 1).min(line.len()),
+        false => line.len(),
+    }
+    .min(start + UNDERLINE_LIMIT)
+    .max(start + 1);
+
+    let left = start.saturating_sub(SNIPPET_CONTEXT);
+    let right = (end + SNIPPET_CONTEXT).min(line.len());
+
+    let mut snippet = String::new();
+    let mut padding = start - left;
+
+    if left > 0 {
+        snippet.push_str(ELLIPSIS);
+        padding += ELLIPSIS.len();
+    }
+    snippet.extend(&line[left..right.max(left)]);
+    if right < line.len() {
+        snippet.push_str(ELLIPSIS);
+    }
+
+    format!(
+        "{}\n{}{}",
+        snippet,
+        " ".repeat(padding),
+        "^".repeat(end - start)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsl_interpreter::lexer::Location;
+
+    fn span(line: usize, start: usize, end_line: usize, end: usize) -> SourceSpan {
+        SourceSpan {
+            start: Location {
+                line,
+                column: start,
+            },
+            end: Location {
+                line: end_line,
+                column: end,
+            },
+        }
+    }
+
+    #[test]
+    fn render_underlined_code() {
+        let code = "print(\"a\")\nadd(1, add(2, \"b\"))";
+        let error = FslError::semantic("add", "all arguments must be of type Number")
+            .within("add", span(2, 8, 2, 19))
+            .within("add", span(2, 1, 2, 20));
+
+        assert_eq!(
+            error.render(code),
+            "Semantic error on line 2, column 8\n```\nadd(1, add(2, \"b\"))\n       ^^^^^^^^^^^\n```\nCommand: add\nDescription: all arguments must be of type Number"
+        );
+
+        let error = FslError::syntax("Misplaced closing quote", span(1, 3, 3, 1));
+        assert_eq!(
+            error.render("a\tb c\nd"),
+            "Syntax error on line 1, column 3\n```\na b c\n  ^^^\n```\nDescription: Misplaced closing quote"
+        );
+    }
+
+    #[test]
+    fn shorten_long_lines() {
+        let code = format!("{}add(1){}", "x".repeat(100), "y".repeat(100));
+        let snippet = underline(&code, &span(1, 101, 1, 107));
+
+        assert_eq!(
+            snippet,
+            format!(
+                "...{}add(1){}...\n{}^^^^^^",
+                "x".repeat(40),
+                "y".repeat(40),
+                " ".repeat(43)
+            )
+        );
+    }
+}
@@ -1,4 +1,7 @@
 use core::panic;
+use std::ops::Range;
+
+use super::error::FslError;
 
 pub const COMMA: &str = ",";
 pub const OPENING_PARENTHESIS: &str = "(";
@@ -27,19 +30,73 @@ pub enum TokenType {
     Keyword,
 }
 
+/// A position in the code. Lines and columns both start at 1 and columns count characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    fn new(code: &str, byte: usize) -> Location {
+        let before = &code[..byte];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+        Location {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+/// The first position of a piece of code and the position just after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub start: Location,
+    pub end: Location,
+}
+
+impl SourceSpan {
+    pub fn new(code: &str, span: &Range<usize>) -> SourceSpan {
+        SourceSpan {
+            start: Location::new(code, span.start),
+            end: Location::new(code, span.end),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Token {
     pub token_type: TokenType,
     pub value: String,
+    /// Byte range of the token in the code. Text spans everything between its quotes, including
+    /// escape characters.
+    pub span: Range<usize>,
+    /// Lines and columns of the byte range.
+    pub location: SourceSpan,
+}
+
+/// Returns the byte range of the text once surrounding whitespace is trimmed.
+fn trimmed_span(text: &str, start: usize) -> Range<usize> {
+    let trimmed_start = start + text.len() - text.trim_start().len();
+    trimmed_start..(start + text.trim_end().len()).max(trimmed_start)
 }
 
-pub fn tokenize(code: &str) -> Vec<Token> {
+pub fn tokenize(code: &str) -> Result<Vec<Token>, FslError> {
     let mut tokens = Vec::new();
+    let new_token = |token_type: TokenType, value: String, span: Range<usize>| Token {
+        token_type,
+        value,
+        location: SourceSpan::new(code, &span),
+        span,
+    };
     let mut buffer = String::new();
+    let mut buffer_start = 0;
+    let mut text_start = 0;
     let mut inside_quote = false;
     let mut incomplete_string: Option<String> = None;
 
-    for c in code.chars() {
+    for (i, c) in code.char_indices() {
         buffer.push(c);
 
         let symbols: &[&str];
@@ -76,33 +133,35 @@ pub fn tokenize(code: &str) -> Vec<Token> {
 
                         text.push_str(left);
 
-                        tokens.push(Token {
-                            token_type: TokenType::Text,
-                            value: text,
-                        });
-                        tokens.push(Token {
-                            token_type: TokenType::ClosingQuote,
-                            value: QUOTE.to_string(),
-                        });
+                        tokens.push(new_token(TokenType::Text, text, text_start..i));
+                        tokens.push(new_token(
+                            TokenType::ClosingQuote,
+                            QUOTE.to_string(),
+                            i..i + 1,
+                        ));
                     } else {
                         inside_quote = true;
+                        text_start = i + 1;
 
-                        tokens.push(Token {
-                            token_type: TokenType::OpeningQuote,
-                            value: QUOTE.to_string(),
-                        });
+                        tokens.push(new_token(
+                            TokenType::OpeningQuote,
+                            QUOTE.to_string(),
+                            i..i + 1,
+                        ));
                     }
                 }
                 OPENING_PARENTHESIS if !inside_quote => {
-                    tokens.push(Token {
-                        token_type: TokenType::Command,
-                        value: left.trim().to_string(),
-                    });
-
-                    tokens.push(Token {
-                        token_type: TokenType::OpeningParenthesis,
-                        value: OPENING_PARENTHESIS.to_string(),
-                    });
+                    tokens.push(new_token(
+                        TokenType::Command,
+                        left.trim().to_string(),
+                        trimmed_span(left, buffer_start),
+                    ));
+
+                    tokens.push(new_token(
+                        TokenType::OpeningParenthesis,
+                        OPENING_PARENTHESIS.to_string(),
+                        i..i + 1,
+                    ));
                 }
                 CLOSING_PARENTHESIS if !inside_quote => {
                     if buffer != CLOSING_PARENTHESIS {
@@ -116,16 +175,18 @@ pub fn tokenize(code: &str) -> Vec<Token> {
                             token_type = TokenType::Identifier
                         }
 
-                        tokens.push(Token {
+                        tokens.push(new_token(
                             token_type,
-                            value: left.trim().to_string(),
-                        });
+                            left.trim().to_string(),
+                            trimmed_span(left, buffer_start),
+                        ));
                     }
 
-                    tokens.push(Token {
-                        token_type: TokenType::ClosingParenthesis,
-                        value: CLOSING_PARENTHESIS.to_string(),
-                    });
+                    tokens.push(new_token(
+                        TokenType::ClosingParenthesis,
+                        CLOSING_PARENTHESIS.to_string(),
+                        i..i + 1,
+                    ));
                 }
                 COMMA if !inside_quote => {
                     if buffer != COMMA {
@@ -139,16 +200,14 @@ pub fn tokenize(code: &str) -> Vec<Token> {
                             token_type = TokenType::Identifier
                         }
 
-                        tokens.push(Token {
+                        tokens.push(new_token(
                             token_type,
-                            value: left.trim().to_string(),
-                        });
+                            left.trim().to_string(),
+                            trimmed_span(left, buffer_start),
+                        ));
                     }
 
-                    tokens.push(Token {
-                        token_type: TokenType::Comma,
-                        value: COMMA.to_string(),
-                    });
+                    tokens.push(new_token(TokenType::Comma, COMMA.to_string(), i..i + 1));
                 }
                 _ => {
                     if !inside_quote {
@@ -159,10 +218,18 @@ pub fn tokenize(code: &str) -> Vec<Token> {
                 }
             }
             buffer.clear();
+            buffer_start = i + c.len_utf8();
         }
     }
 
-    tokens
+    if inside_quote {
+        return Err(FslError::syntax(
+            "Opening quote is never closed",
+            SourceSpan::new(code, &(text_start - 1..text_start)),
+        ));
+    }
+
+    Ok(tokens)
 }
 
 fn get_symbol<'a>(code: &str, symbols: &[&'a str]) -> Option<&'a str> {
@@ -182,7 +249,7 @@ mod tests {
     fn standard_tokens() {
         let code = "add(\"'noun\", \"'puncuation\") multiply(2, identifier)";
 
-        let tokens = tokenize(code);
+        let tokens = tokenize(code).unwrap();
 
         assert_eq!(tokens[0].token_type, TokenType::Command);
         assert_eq!(tokens[0].value, "add".to_string());
@@ -228,7 +295,7 @@ mod tests {
     #[test]
     fn escaped_quotes() {
         let code = "print(\"\\\"qu\\\"o\\\"te\\\"\")";
-        let tokens = tokenize(code);
+        let tokens = tokenize(code).unwrap();
         dbg!(&tokens[3]);
         assert_eq!(tokens[3].token_type, TokenType::Text);
         assert_eq!(tokens[3].value, "\"qu\"o\"te\"".to_string());
@@ -238,7 +305,7 @@ mod tests {
     fn symbols_inside_quotes() {
         let code = "print(\" example of, some, (), symbols \\\" inside, of quotes \")";
 
-        let tokens = tokenize(code);
+        let tokens = tokenize(code).unwrap();
 
         assert_eq!(tokens[0].token_type, TokenType::Command);
         assert_eq!(tokens[0].value, "print".to_string());
@@ -260,10 +327,56 @@ mod tests {
         // dbg!(tokens);
     }
 
+    #[test]
+    fn token_spans() {
+        let code = "get_sub ( \"no\\\"un\" ), x";
+        let tokens = tokenize(code).unwrap();
+
+        let spans: Vec<&str> = tokens.iter().map(|t| &code[t.span.clone()]).collect();
+        assert_eq!(
+            spans,
+            vec!["get_sub", "(", "\"", "no\\\"un", "\"", "", ")", ","]
+        );
+        assert_eq!(tokens[3].value, "no\"un");
+    }
+
+    #[test]
+    fn token_locations() {
+        let code = "print(\n  \"é\", add(1,\n2))";
+        let tokens = tokenize(code).unwrap();
+
+        let locations: Vec<(usize, usize)> = tokens
+            .iter()
+            .map(|t| (t.location.start.line, t.location.start.column))
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                (1, 1),
+                (1, 6),
+                (2, 3),
+                (2, 4),
+                (2, 5),
+                (2, 6),
+                (2, 8),
+                (2, 11),
+                (2, 12),
+                (2, 13),
+                (3, 1),
+                (3, 2),
+                (3, 3)
+            ]
+        );
+        assert_eq!(tokens[0].location.end.column, 6);
+
+        let error = tokenize("print(\"text)").unwrap_err();
+        assert_eq!(error.location.unwrap().start.column, 7);
+    }
+
     #[test]
     fn keyword_token() {
         let code = "print(true)";
-        let tokens = tokenize(code);
+        let tokens = tokenize(code).unwrap();
         assert_eq!(tokens[0].token_type, TokenType::Command);
         assert_eq!(tokens[0].value, "print".to_string());
 
@@ -1,15 +1,19 @@
+use std::collections::BTreeMap;
 use std::mem::size_of;
 use std::str::FromStr;
 
+use serde_json::{Number, Value};
+
 use crate::fsl_interpreter::lexer::KEYWORD_FALSE;
 use crate::fsl_interpreter::lexer::KEYWORD_TRUE;
 
+use super::error::FslError;
+use super::lexer::SourceSpan;
 use super::lexer::Token;
 use super::lexer::TokenType;
 
 pub const COMMAND_STACK_EXPECT: &str = "Command stack should have at least one command";
 pub const TOKEN_VALUE_EXCEPT: &str = "Token must have value";
-pub const ERR_LOCATION_WIDTH: usize = 3;
 
 // General purpose
 pub const PRINT: &str = "print";
@@ -25,6 +29,8 @@ pub const RANDOM_RANGE: &str = "random_range";
 // Variables
 pub const COPY: &str = "copy";
 pub const PASTE: &str = "paste";
+pub const STORE: &str = "store";
+pub const LOAD: &str = "load";
 
 // Booleans
 pub const EQ: &str = "eq";
@@ -55,12 +61,23 @@ pub const INSERT: &str = "insert";
 pub const REMOVE: &str = "remove";
 pub const REPLACE: &str = "replace";
 
+// Maps
+pub const MAP: &str = "map";
+pub const MAP_GET: &str = "map_get";
+pub const MAP_SET: &str = "map_set";
+pub const MAP_REMOVE: &str = "map_remove";
+pub const MAP_KEYS: &str = "map_keys";
+pub const MAP_HAS: &str = "map_has";
+
 // Control flow
 pub const IF_THEN: &str = "if_then";
 pub const IF_THEN_ELSE: &str = "if_then_else";
 pub const REPEAT: &str = "repeat";
 pub const WHILE: &str = "while";
 
+// Functions
+pub const DEF: &str = "def";
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum CommandType {
     Add,
@@ -99,6 +116,17 @@ pub enum CommandType {
     Insert,
     Remove,
     Replace,
+    Def,
+    Store,
+    Load,
+    Map,
+    MapGet,
+    MapSet,
+    MapRemove,
+    MapKeys,
+    MapHas,
+    /// A call to a function defined with def.
+    Call(String),
 }
 
 impl CommandType {
@@ -140,15 +168,21 @@ impl CommandType {
             CommandType::Insert => INSERT,
             CommandType::Remove => REMOVE,
             CommandType::Replace => REPLACE,
+            CommandType::Def => DEF,
+            CommandType::Store => STORE,
+            CommandType::Load => LOAD,
+            CommandType::Map => MAP,
+            CommandType::MapGet => MAP_GET,
+            CommandType::MapSet => MAP_SET,
+            CommandType::MapRemove => MAP_REMOVE,
+            CommandType::MapKeys => MAP_KEYS,
+            CommandType::MapHas => MAP_HAS,
+            CommandType::Call(name) => name,
         }
     }
 
-    pub fn gen_err(&self, description: &str) -> String {
-        format!(
-            "Semantic\nCommand: {}\nDescription: {}",
-            self.to_str(),
-            description
-        )
+    pub fn gen_err(&self, description: &str) -> FslError {
+        FslError::semantic(self.to_str(), description)
     }
 }
 
@@ -193,6 +227,15 @@ impl FromStr for CommandType {
             INSERT => Ok(CommandType::Insert),
             REMOVE => Ok(CommandType::Remove),
             REPLACE => Ok(CommandType::Replace),
+            DEF => Ok(CommandType::Def),
+            STORE => Ok(CommandType::Store),
+            LOAD => Ok(CommandType::Load),
+            MAP => Ok(CommandType::Map),
+            MAP_GET => Ok(CommandType::MapGet),
+            MAP_SET => Ok(CommandType::MapSet),
+            MAP_REMOVE => Ok(CommandType::MapRemove),
+            MAP_KEYS => Ok(CommandType::MapKeys),
+            MAP_HAS => Ok(CommandType::MapHas),
             _ => Err(format!("Invalid command {}", s)),
         }
     }
@@ -205,6 +248,8 @@ pub enum ValueType {
     Float(f64),
     Bool(bool),
     List(Vec<ValueType>),
+    /// Values keyed by Text, kept in order of their keys.
+    Map(BTreeMap<String, ValueType>),
     Identifier(String),
     Command(Command),
     None,
@@ -226,6 +271,52 @@ impl ValueType {
         }
     }
 
+    /// Converts the value to JSON so it can be stored. Returns None for values that can't be
+    /// stored, such as Identifiers, Commands and Floats that aren't finite.
+    pub fn to_json(&self) -> Option<Value> {
+        match self {
+            ValueType::Text(value) => Some(Value::String(value.clone())),
+            ValueType::Int(value) => Some(Value::from(*value)),
+            ValueType::Float(value) => Number::from_f64(*value).map(Value::Number),
+            ValueType::Bool(value) => Some(Value::Bool(*value)),
+            ValueType::List(values) => values
+                .iter()
+                .map(ValueType::to_json)
+                .collect::<Option<Vec<Value>>>()
+                .map(Value::Array),
+            ValueType::Map(values) => values
+                .iter()
+                .map(|(key, value)| Some((key.clone(), value.to_json()?)))
+                .collect::<Option<serde_json::Map<String, Value>>>()
+                .map(Value::Object),
+            ValueType::Identifier(_) => None,
+            ValueType::Command(_) => None,
+            ValueType::None => None,
+        }
+    }
+
+    pub fn from_json(value: &Value) -> Option<ValueType> {
+        match value {
+            Value::String(value) => Some(ValueType::Text(value.clone())),
+            Value::Number(value) => match value.as_i64() {
+                Some(value) => Some(ValueType::Int(value)),
+                None => value.as_f64().map(ValueType::Float),
+            },
+            Value::Bool(value) => Some(ValueType::Bool(*value)),
+            Value::Array(values) => values
+                .iter()
+                .map(ValueType::from_json)
+                .collect::<Option<Vec<ValueType>>>()
+                .map(ValueType::List),
+            Value::Object(values) => values
+                .iter()
+                .map(|(key, value)| Some((key.clone(), ValueType::from_json(value)?)))
+                .collect::<Option<BTreeMap<String, ValueType>>>()
+                .map(ValueType::Map),
+            Value::Null => None,
+        }
+    }
+
     pub fn get_size(&self) -> usize {
         match self {
             ValueType::Text(value) => size_of::<ValueType>() + value.capacity(),
@@ -234,6 +325,12 @@ impl ValueType {
             ValueType::Bool(_) => size_of::<ValueType>(),
             ValueType::List(values) => size_of::<ValueType>()
                 .saturating_add(values.iter().map(|value| value.get_size()).sum()),
+            ValueType::Map(values) => size_of::<ValueType>().saturating_add(
+                values
+                    .iter()
+                    .map(|(key, value)| key.capacity().saturating_add(value.get_size()))
+                    .sum(),
+            ),
             ValueType::Identifier(value) => size_of::<ValueType>() + value.capacity(),
             ValueType::Command(value) => size_of::<ValueType>()
                 .saturating_add(value.args.iter().map(|value| value.get_size()).sum()),
@@ -256,6 +353,13 @@ impl ToString for ValueType {
                     .collect();
                 format!("[{}]", &list_string[0..list_string.len() - 2])
             }
+            ValueType::Map(values) => {
+                let entries: Vec<String> = values
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value.to_string()))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
             ValueType::Identifier(value) => value.to_string(),
             ValueType::Command(value) => value.command_type.to_str().to_string(),
             ValueType::None => "".to_string(),
@@ -263,20 +367,33 @@ impl ToString for ValueType {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub struct Command {
     pub command_type: CommandType,
     pub args: Vec<ValueType>,
+    /// From the start of the command name to its closing parenthesis.
+    pub location: SourceSpan,
 }
 
 impl Command {
-    fn from(command_name: &str) -> Result<Command, String> {
-        let command_type = CommandType::from_str(command_name)?;
+    /// Creates a command with no arguments. Names that aren't a built in command are function
+    /// calls.
+    fn from(command_name: &str, location: SourceSpan) -> Command {
+        let command_type = CommandType::from_str(command_name)
+            .unwrap_or_else(|_| CommandType::Call(command_name.to_string()));
 
-        Ok(Command {
+        Command {
             command_type,
             args: Vec::new(),
-        })
+            location,
+        }
+    }
+}
+
+/// Commands are equal if they evaluate the same way, wherever they're written.
+impl PartialEq for Command {
+    fn eq(&self, other: &Self) -> bool {
+        self.command_type == other.command_type && self.args == other.args
     }
 }
 
@@ -300,22 +417,8 @@ impl<'a> TokenIndex<'a> {
         }
     }
 
-    pub fn gen_err(&self, description: &str) -> String {
-        let mut err = "Syntax\nLocation: ".to_string();
-
-        let left = self.index.saturating_sub(ERR_LOCATION_WIDTH);
-        let right = match self.index + ERR_LOCATION_WIDTH > self.tokens.len() {
-            true => self.tokens.len(),
-            false => self.index + ERR_LOCATION_WIDTH,
-        };
-
-        for token in &self.tokens[left..right] {
-            err.push_str(&token.value);
-        }
-
-        err.push_str(&format!("\nDescription: {}", description.to_string()));
-
-        err
+    pub fn gen_err(&self, description: &str) -> FslError {
+        FslError::syntax(description, self.tokens[self.index].location)
     }
 }
 
@@ -324,7 +427,7 @@ struct CommandIndex<'a> {
     token_index: TokenIndex<'a>,
 }
 
-pub fn parse(tokens: Vec<Token>) -> Result<Vec<Command>, String> {
+pub fn parse(tokens: Vec<Token>) -> Result<Vec<Command>, FslError> {
     let mut commands: Vec<Command> = Vec::new();
 
     let mut command_stack: Vec<CommandIndex> = Vec::new();
@@ -364,7 +467,9 @@ pub fn parse(tokens: Vec<Token>) -> Result<Vec<Command>, String> {
                 }
             }
             TokenType::ClosingParenthesis => match command_stack.pop() {
-                Some(command_index) => {
+                Some(mut command_index) => {
+                    command_index.command.location.end = token.location.end;
+
                     if command_stack.len() == 0 {
                         commands.push(command_index.command)
                     } else {
@@ -396,7 +501,7 @@ pub fn parse(tokens: Vec<Token>) -> Result<Vec<Command>, String> {
                     );
                 } else {
                     command_stack.push(CommandIndex {
-                        command: Command::from(&token.value)?,
+                        command: Command::from(&token.value, token.location),
                         token_index,
                     });
                 }
@@ -507,17 +612,30 @@ pub fn parse(tokens: Vec<Token>) -> Result<Vec<Command>, String> {
 mod tests {
 
     use crate::fsl_interpreter::{
-        lexer::tokenize,
+        lexer::{tokenize, Location, SourceSpan},
         parser::{Command, CommandType, ValueType},
     };
 
     use super::parse;
 
+    fn command(command_type: CommandType, args: Vec<ValueType>) -> Command {
+        let location = Location { line: 1, column: 1 };
+
+        Command {
+            command_type,
+            args,
+            location: SourceSpan {
+                start: location,
+                end: location,
+            },
+        }
+    }
+
     #[test]
     fn simple_parse() {
         let code = "add(5, 10)";
 
-        let commands = parse(tokenize(code)).unwrap();
+        let commands = parse(tokenize(code).unwrap()).unwrap();
 
         assert_eq!(commands[0].command_type, CommandType::Add);
         assert_eq!(
@@ -532,17 +650,17 @@ mod tests {
     fn logical_parse() {
         let code = "if_then(true, print(\"true\"))";
 
-        let commands = parse(tokenize(code)).unwrap();
+        let commands = parse(tokenize(code).unwrap()).unwrap();
 
         assert_eq!(commands[0].command_type, CommandType::IfThen);
         assert_eq!(
             commands[0].args,
             vec![
                 ValueType::Bool(true),
-                ValueType::Command(Command {
-                    command_type: CommandType::Print,
-                    args: vec![ValueType::Text("true".to_string())],
-                })
+                ValueType::Command(command(
+                    CommandType::Print,
+                    vec![ValueType::Text("true".to_string())]
+                ))
             ]
         )
     }
@@ -551,31 +669,93 @@ mod tests {
     fn nested_parse() {
         let code = "add(add(identifier, \"text\"), add(add(1, 2), 2))";
 
-        let commands = parse(tokenize(code)).unwrap();
+        let commands = parse(tokenize(code).unwrap()).unwrap();
 
         assert_eq!(commands[0].command_type, CommandType::Add);
         assert_eq!(
             commands[0].args,
             vec![
-                ValueType::Command(Command {
-                    command_type: CommandType::Add,
-                    args: vec![
+                ValueType::Command(command(
+                    CommandType::Add,
+                    vec![
                         ValueType::Identifier("identifier".to_string()),
                         ValueType::Text("text".to_string())
-                    ],
-                }),
-                ValueType::Command(Command {
-                    command_type: CommandType::Add,
-                    args: vec![
-                        ValueType::Command(Command {
-                            command_type: CommandType::Add,
-                            args: vec![ValueType::Int(1), ValueType::Int(2)]
-                        }),
+                    ]
+                )),
+                ValueType::Command(command(
+                    CommandType::Add,
+                    vec![
+                        ValueType::Command(command(
+                            CommandType::Add,
+                            vec![ValueType::Int(1), ValueType::Int(2)]
+                        )),
                         ValueType::Int(2)
                     ]
-                })
+                ))
             ]
         );
         // dbg!(commands);
     }
+
+    #[test]
+    fn function_call_parse() {
+        let code = "def(twice, x, mul(paste(x), 2)) twice(4)";
+
+        let commands = parse(tokenize(code).unwrap()).unwrap();
+
+        assert_eq!(commands[0].command_type, CommandType::Def);
+        assert_eq!(
+            commands[1],
+            command(
+                CommandType::Call("twice".to_string()),
+                vec![ValueType::Int(4)]
+            )
+        );
+    }
+
+    #[test]
+    fn command_locations() {
+        let code = "add(1,\n  add(2, 3))";
+
+        let commands = parse(tokenize(code).unwrap()).unwrap();
+
+        let outer = commands[0].location;
+        assert_eq!((outer.start.line, outer.start.column), (1, 1));
+        assert_eq!((outer.end.line, outer.end.column), (2, 13));
+
+        let ValueType::Command(inner) = &commands[0].args[1] else {
+            panic!("second argument should be a command");
+        };
+        assert_eq!(
+            (inner.location.start.line, inner.location.start.column),
+            (2, 3)
+        );
+        assert_eq!(
+            (inner.location.end.line, inner.location.end.column),
+            (2, 12)
+        );
+
+        let error = parse(tokenize("add(1, 2) add(3\n, 4").unwrap()).unwrap_err();
+        let location = error.location.unwrap();
+        assert_eq!((location.start.line, location.start.column), (1, 11));
+    }
+
+    #[test]
+    fn map_values() {
+        let map = ValueType::Map(
+            [
+                (
+                    "b".to_string(),
+                    ValueType::List(vec![ValueType::Float(1.5)]),
+                ),
+                ("a".to_string(), ValueType::Text("text".to_string())),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(map.to_string(), "{a: text, b: [1.5]}");
+        assert!(map.get_size() > ValueType::Text("text".to_string()).get_size());
+        assert_eq!(ValueType::from_json(&map.to_json().unwrap()), Some(map));
+    }
 }
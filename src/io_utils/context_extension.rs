@@ -21,6 +21,17 @@ pub trait ContextExtension {
     async fn say_ephemeral(&self, message: &str) -> Result<(), Error>;
 
     async fn say_long(&self, message: &str, ephemeral: bool) -> Result<(), Error>;
+
+    /// Returns the id of the namespace templates are read from and written to.
+    ///
+    /// Commands used in a guild use the guild's id while direct messages use the author's id.
+    fn template_guild_id(&self) -> u64;
+
+    /// Returns true if the author has the Manage Server permission.
+    ///
+    /// Slash commands read the permission from the interaction, other commands request it. Always
+    /// true in direct messages since the author owns that template namespace.
+    async fn author_can_manage_guild(&self) -> Result<bool, Error>;
 }
 
 impl<'a> ContextExtension for Context<'a> {
@@ -101,4 +112,29 @@ impl<'a> ContextExtension for Context<'a> {
         }
         Ok(())
     }
+
+    fn template_guild_id(&self) -> u64 {
+        match self.guild_id() {
+            Some(guild_id) => guild_id.get(),
+            None => self.author().id.get(),
+        }
+    }
+
+    async fn author_can_manage_guild(&self) -> Result<bool, Error> {
+        // Interactions include the author's permissions so they don't need to be requested.
+        if let poise::Context::Application(ctx) = self {
+            if let Some(permissions) = ctx.interaction.member.as_ref().and_then(|m| m.permissions) {
+                return Ok(permissions.manage_guild());
+            }
+        }
+
+        match self.guild_id() {
+            Some(guild_id) => {
+                let guild = guild_id.to_partial_guild(self).await?;
+                let member = guild.member(self, self.author().id).await?;
+                Ok(guild.member_permissions(&member).manage_guild())
+            }
+            None => Ok(true),
+        }
+    }
 }
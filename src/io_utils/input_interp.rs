@@ -1,28 +1,357 @@
-use std::sync::Arc;
+use std::cmp::Reverse;
+use std::ops::Range;
+use std::sync::{Arc, PoisonError};
 
+use rand::{rngs::StdRng, SeedableRng};
 use tokio::sync::Mutex;
 
 use crate::{
-    fsl_interpreter::Interpreter, storage::template_database::FunboyDatabase,
-    text_interpolator::TextInterpolator,
+    fsl_interpreter::{CodeEvaluation, ExecutionBudget, Interpreter},
+    storage::template_database::FunboyDatabase,
+    text_interpolator::{
+        choices::expand_choices, Expansion, Substitute, SubstituteProvider, TextInterpolator,
+    },
 };
 
-pub async fn interp_input(input: String, db: Arc<Mutex<FunboyDatabase>>) -> Result<String, String> {
-    let mut interpolator = TextInterpolator::default();
+/// Random number generator shared by every random choice made while generating text, so a seed
+/// reproduces the whole generation.
+pub type GenerationRng = Arc<std::sync::Mutex<StdRng>>;
+
+pub fn seeded_rng(seed: u64) -> GenerationRng {
+    Arc::new(std::sync::Mutex::new(StdRng::seed_from_u64(seed)))
+}
+
+/// Provides random substitutes from a guild's templates, drawing from the channel's deck for
+/// templates in deck mode.
+///
+/// The database is only locked while each template is looked up so other generations can use it
+/// in between.
+#[derive(Debug, Clone)]
+pub struct DatabaseSubstituteProvider {
+    db: Arc<Mutex<FunboyDatabase>>,
+    guild_id: u64,
+    channel_id: u64,
+    rng: GenerationRng,
+}
+
+impl DatabaseSubstituteProvider {
+    pub fn new(
+        db: Arc<Mutex<FunboyDatabase>>,
+        guild_id: u64,
+        channel_id: u64,
+        rng: GenerationRng,
+    ) -> Self {
+        DatabaseSubstituteProvider {
+            db,
+            guild_id,
+            channel_id,
+            rng,
+        }
+    }
+}
+
+impl SubstituteProvider for DatabaseSubstituteProvider {
+    async fn get_substitute(&self, template: &str) -> Option<Substitute> {
+        let mut db = self.db.lock().await;
+        let mut rng = self.rng.lock().unwrap_or_else(PoisonError::into_inner);
+        let record = db.get_random_sub_record(self.guild_id, self.channel_id, template, &mut *rng);
+
+        match record {
+            Ok(Some(record)) => Some(Substitute {
+                text: expand_choices(&record.name, &mut *rng),
+                id: Some(i64::from(record.id)),
+            }),
+            Ok(None) => Some(Substitute::from(String::new())),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Everything that went into generating a piece of text, nested under what it came from.
+#[derive(Debug)]
+pub struct Explanation {
+    pub nodes: Vec<ExplanationNode>,
+}
+
+#[derive(Debug)]
+pub enum ExplanationNode {
+    /// A substituted template. Its nested expansions are moved into `children`.
+    Template {
+        expansion: Expansion,
+        children: Vec<ExplanationNode>,
+    },
+    /// A block of embedded code along with the templates and code inside of it and the templates
+    /// it substituted with get_sub.
+    Code {
+        evaluation: CodeEvaluation,
+        children: Vec<ExplanationNode>,
+    },
+}
+
+impl ExplanationNode {
+    fn span(&self) -> &Range<usize> {
+        match self {
+            ExplanationNode::Template { expansion, .. } => &expansion.span,
+            ExplanationNode::Code { evaluation, .. } => &evaluation.span,
+        }
+    }
+
+    fn children_mut(&mut self) -> &mut Vec<ExplanationNode> {
+        match self {
+            ExplanationNode::Template { children, .. } => children,
+            ExplanationNode::Code { children, .. } => children,
+        }
+    }
+}
+
+impl Explanation {
+    /// Nests every block of code under the innermost template or block of code it was written
+    /// in.
+    ///
+    /// Spans of the expansions and evaluations must refer to the same interpolated text.
+    fn new(expansions: Vec<Expansion>, mut evaluated_code: Vec<CodeEvaluation>) -> Explanation {
+        let mut nodes = template_nodes(expansions);
+
+        // Outer blocks first so inner blocks can be placed inside of them.
+        evaluated_code
+            .sort_by_key(|evaluation| (evaluation.span.start, Reverse(evaluation.span.end)));
+
+        for evaluation in evaluated_code {
+            insert_code(&mut nodes, evaluation);
+        }
+
+        add_code_expansions(&mut nodes);
+
+        Explanation { nodes }
+    }
+}
+
+fn contains(outer: &Range<usize>, inner: &Range<usize>) -> bool {
+    outer.start <= inner.start && inner.end <= outer.end
+}
 
-    let fdb = db.lock().await;
-    let output = interpolator.interp(&input, &|template| match fdb.get_random_subs(template) {
-        Ok(sub) => Some(sub),
-        Err(_) => None,
+fn template_nodes(expansions: Vec<Expansion>) -> Vec<ExplanationNode> {
+    expansions
+        .into_iter()
+        .map(|mut expansion| ExplanationNode::Template {
+            children: template_nodes(std::mem::take(&mut expansion.children)),
+            expansion,
+        })
+        .collect()
+}
+
+/// Places a block of code under the innermost node containing it, taking the nodes it contains
+/// as its children.
+fn insert_code(nodes: &mut Vec<ExplanationNode>, evaluation: CodeEvaluation) {
+    if let Some(parent) = nodes
+        .iter_mut()
+        .find(|node| contains(node.span(), &evaluation.span))
+    {
+        return insert_code(parent.children_mut(), evaluation);
+    }
+
+    let (children, mut siblings): (Vec<_>, Vec<_>) = std::mem::take(nodes)
+        .into_iter()
+        .partition(|node| contains(&evaluation.span, node.span()));
+
+    siblings.push(ExplanationNode::Code {
+        evaluation,
+        children,
     });
-    drop(fdb);
+    siblings.sort_by_key(|node| node.span().start);
+    *nodes = siblings;
+}
 
-    let mut fsl_interpreter = Interpreter::new_with_db(db);
+/// Adds the templates substituted by get_sub to the block of code that substituted them.
+///
+/// Done once every block is placed since their spans refer to the text get_sub returned.
+fn add_code_expansions(nodes: &mut [ExplanationNode]) {
+    for node in nodes {
+        if let ExplanationNode::Code {
+            evaluation,
+            children,
+        } = node
+        {
+            add_code_expansions(children);
+            children.extend(template_nodes(std::mem::take(&mut evaluation.expansions)));
+        } else {
+            add_code_expansions(node.children_mut());
+        }
+    }
+}
+
+pub async fn interp_input(
+    input: String,
+    db: Arc<Mutex<FunboyDatabase>>,
+    guild_id: u64,
+    channel_id: u64,
+    user_id: u64,
+    budget: ExecutionBudget,
+) -> Result<String, String> {
+    let (output, _) = interp_input_explained(
+        input,
+        db,
+        guild_id,
+        channel_id,
+        user_id,
+        rand::random(),
+        budget,
+    )
+    .await?;
+    Ok(output)
+}
+
+/// Interpolates input like [`interp_input`] while recording every template substituted and every
+/// block of code evaluated.
+///
+/// Every random choice is made with a generator seeded by the seed so the same seed, input and
+/// templates generate the same output. Embedded code stops once it has spent the budget.
+pub async fn interp_input_explained(
+    input: String,
+    db: Arc<Mutex<FunboyDatabase>>,
+    guild_id: u64,
+    channel_id: u64,
+    user_id: u64,
+    seed: u64,
+    budget: ExecutionBudget,
+) -> Result<(String, Explanation), String> {
+    let rng = seeded_rng(seed);
+    let mut interpolator = TextInterpolator::default();
+    let input = expand_choices(
+        &input,
+        &mut *rng.lock().unwrap_or_else(PoisonError::into_inner),
+    );
+
+    let provider = DatabaseSubstituteProvider::new(db.clone(), guild_id, channel_id, rng.clone());
+    let output = interpolator.interpolate(&input, &provider).await;
+
+    let mut fsl_interpreter =
+        Interpreter::new_with_db(db, guild_id, channel_id, user_id, rng, budget);
+    fsl_interpreter.set_interpolator(interpolator);
     match output {
-        Ok(output) => match fsl_interpreter.interpret_embedded_code(&output).await {
-            Ok(o) => Ok(o),
+        Ok(output) => match fsl_interpreter.interpret_embedded_code(&output.text).await {
+            Ok(o) => Ok((
+                o,
+                Explanation::new(
+                    output.expansions,
+                    fsl_interpreter.get_evaluated_code().to_vec(),
+                ),
+            )),
             Err(e) => Err(e),
         },
         Err(e) => Err(e.to_string()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn share_bindings_with_code() {
+        let mut db = FunboyDatabase::from_path("test27.db").unwrap();
+        db.clear().unwrap();
+        db.insert_subs(1, 1, "name", Some(&["Ann", "Bob", "Cat", "Dan", "Eve"]))
+            .unwrap();
+        db.insert_subs(1, 1, "greeting", Some(&["hi ^name#1"]))
+            .unwrap();
+        let db = Arc::new(Mutex::new(db));
+
+        for seed in 0..10 {
+            let (output, _) = interp_input_explained(
+                "^name#1 {get_sub(\"name#1\")} {get_sub(\"greeting\")}".to_string(),
+                db.clone(),
+                1,
+                1,
+                1,
+                seed,
+                ExecutionBudget::default(),
+            )
+            .await
+            .unwrap();
+
+            let words: Vec<&str> = output.split_whitespace().collect();
+            assert_eq!(words.len(), 4);
+            assert_eq!(words[0], words[1]);
+            assert_eq!(words[0], words[3]);
+        }
+    }
+
+    fn describe(nodes: &[ExplanationNode], depth: usize, lines: &mut Vec<String>) {
+        for node in nodes {
+            let (line, children) = match node {
+                ExplanationNode::Template {
+                    expansion,
+                    children,
+                } => (format!("^{}", expansion.template), children),
+                ExplanationNode::Code {
+                    evaluation,
+                    children,
+                } => (format!("{{{}}}", evaluation.code), children),
+            };
+
+            lines.push(format!("{}{}", "  ".repeat(depth), line));
+            describe(children, depth + 1, lines);
+        }
+    }
+
+    #[tokio::test]
+    async fn nest_code_in_explanation() {
+        let mut db = FunboyDatabase::from_path("test28.db").unwrap();
+        db.clear().unwrap();
+        db.insert_subs(
+            1,
+            1,
+            "sentence",
+            Some(&["the ^animal says {add(1, {add(1, 1)})}"]),
+        )
+        .unwrap();
+        db.insert_subs(1, 1, "animal", Some(&["cat"])).unwrap();
+        db.insert_subs(1, 1, "sound", Some(&["meow"])).unwrap();
+        let db = Arc::new(Mutex::new(db));
+
+        let (output, explanation) = interp_input_explained(
+            "^sentence {get_sub(\"sound\")}".to_string(),
+            db,
+            1,
+            1,
+            1,
+            0,
+            ExecutionBudget::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output, "the cat says 3 meow");
+
+        let mut lines = Vec::new();
+        describe(&explanation.nodes, 0, &mut lines);
+        assert_eq!(
+            lines,
+            vec![
+                "^sentence",
+                "  ^animal",
+                "  {add(1, 2)}",
+                "    {add(1, 1)}",
+                "{get_sub(\"sound\")}",
+                "  ^sound",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn limit_code_by_budget() {
+        let db = Arc::new(Mutex::new(FunboyDatabase::from_path("test29.db").unwrap()));
+        let budget = ExecutionBudget {
+            max_steps: 2,
+            ..ExecutionBudget::default()
+        };
+        let generate =
+            |input: &str| interp_input_explained(input.to_string(), db.clone(), 1, 1, 1, 0, budget);
+
+        assert_eq!(generate("{add(1, 1)}").await.unwrap().0, "2");
+        assert!(generate("{add(1, add(1, add(1, 1)))}")
+            .await
+            .unwrap_err()
+            .contains("execution budget exceeded"));
+    }
+}
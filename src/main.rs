@@ -1,9 +1,11 @@
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
+    time::Duration,
 };
 
 use ::serenity::all::{ClientBuilder, FullEvent, GatewayIntents, Interaction};
+use fsl_interpreter::ExecutionBudget;
 use io_utils::custom_components::{CustomComponent, TrackComponent};
 use ollama_generator::ollama_generator::{OllamaGenerator, OllamaSettings};
 use reqwest::Client as HttpClient;
@@ -38,6 +40,8 @@ pub struct Data {
     pub ollama_generator: Mutex<OllamaGenerator>,
     pub ollama_model: Arc<Mutex<Option<String>>>,
     pub ollama_settings_map: Arc<Mutex<OllamaSettingsMap>>,
+    /// Limits on the code embedded in each generation, read from the environment.
+    pub fsl_budget: ExecutionBudget,
 
     yt_dlp_cookies_path: Option<String>,
     imgur_client_id: Option<String>,
@@ -93,6 +97,32 @@ async fn main() {
         }
     };
 
+    let default_budget = ExecutionBudget::default();
+    let fsl_budget = ExecutionBudget {
+        max_steps: match std::env::var("FSL_MAX_STEPS").map(|steps| steps.parse()) {
+            Ok(Ok(steps)) if steps > 0 => steps,
+            Ok(_) => {
+                eprintln!(
+                    "FSL_MAX_STEPS must be a whole number greater than 0, using {} instead.",
+                    default_budget.max_steps
+                );
+                default_budget.max_steps
+            }
+            Err(_) => default_budget.max_steps,
+        },
+        max_duration: match std::env::var("FSL_MAX_MILLISECONDS").map(|ms| ms.parse()) {
+            Ok(Ok(milliseconds)) if milliseconds > 0 => Duration::from_millis(milliseconds),
+            Ok(_) => {
+                eprintln!(
+                    "FSL_MAX_MILLISECONDS must be a whole number greater than 0, using {} instead.",
+                    default_budget.max_duration.as_millis()
+                );
+                default_budget.max_duration
+            }
+            Err(_) => default_budget.max_duration,
+        },
+    };
+
     let intents = GatewayIntents::non_privileged();
 
     let framework = poise::Framework::builder()
@@ -114,12 +144,22 @@ async fn main() {
                 commands::text_gen::remove_subs_by_id(),
                 commands::text_gen::remove_template(),
                 commands::text_gen::rename_template(),
+                commands::text_gen::lock_template(),
+                commands::text_gen::deck_mode(),
+                commands::text_gen::export_templates(),
+                commands::text_gen::import_templates(),
+                commands::text_gen::search_subs(),
+                commands::text_gen::lint_templates(),
+                commands::text_gen::history(),
+                commands::text_gen::undo(),
                 commands::text_gen::replace_sub(),
                 commands::text_gen::replace_sub_by_id(),
+                commands::text_gen::set_sub_weight(),
                 commands::text_gen::generate(),
                 commands::text_gen::list(),
                 commands::text_gen::list_ids(),
                 commands::text_gen::list_numerically(),
+                commands::text_gen::use_legacy_templates(),
                 commands::sound::join_voice(),
                 commands::sound::leave_voice(),
                 commands::sound::play_track(),
@@ -161,7 +201,7 @@ async fn main() {
             },
             ..Default::default()
         })
-        .setup(|ctx, _ready, framework| {
+        .setup(move |ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
 
@@ -175,6 +215,7 @@ async fn main() {
                     ollama_generator: Mutex::new(OllamaGenerator::new()),
                     ollama_model: Arc::new(Mutex::new(None)),
                     ollama_settings_map: Arc::new(Mutex::new(OllamaSettingsMap::new())),
+                    fsl_budget,
                     track_list: Mutex::new(TrackList::new()).into(),
                     imgur_client_id,
                     track_player_lock: Arc::new(Mutex::new(())),
@@ -1 +1,5 @@
+pub mod template_cache;
 pub mod template_database;
+pub mod template_export;
+pub mod template_lint;
+pub mod template_references;
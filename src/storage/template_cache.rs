@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use rusqlite::Connection;
+
+use super::template_database::{SubstituteRecord, LEGACY_GUILD_ID};
+
+/// The substitutes of a template that can be picked at random.
+#[derive(Debug)]
+pub struct CachedTemplate {
+    id: i32,
+    deck: bool,
+    substitutes: Vec<SubstituteRecord>,
+    /// None if no substitute has a weight above 0. Weights are summed as u64 so large u32 weights
+    /// can't overflow the total.
+    distribution: Option<WeightedIndex<u64>>,
+}
+
+impl CachedTemplate {
+    fn new(id: i32, deck: bool, substitutes: Vec<SubstituteRecord>) -> CachedTemplate {
+        let distribution =
+            WeightedIndex::new(substitutes.iter().map(|record| u64::from(record.weight))).ok();
+
+        CachedTemplate {
+            id,
+            deck,
+            substitutes,
+            distribution,
+        }
+    }
+
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// True if substitutes are drawn without replacement.
+    pub fn is_deck(&self) -> bool {
+        self.deck
+    }
+
+    /// Picks a random substitute by its weight minus the number of times it was drawn. Returns
+    /// None once every substitute was drawn as many times as its weight.
+    pub fn draw(&self, drawn: &HashMap<i32, u32>, rng: &mut impl Rng) -> Option<&SubstituteRecord> {
+        let remaining = self.substitutes.iter().map(|record| {
+            u64::from(
+                record
+                    .weight
+                    .saturating_sub(drawn.get(&record.id).copied().unwrap_or(0)),
+            )
+        });
+        let distribution = WeightedIndex::new(remaining).ok()?;
+
+        Some(&self.substitutes[distribution.sample(rng)])
+    }
+
+    /// Picks a random substitute by weight. Returns None if no substitute can be picked.
+    pub fn choose(&self, rng: &mut impl Rng) -> Option<&SubstituteRecord> {
+        self.distribution
+            .as_ref()
+            .map(|distribution| &self.substitutes[distribution.sample(rng)])
+    }
+}
+
+/// Every template held in memory so substitutes can be picked without querying the database.
+#[derive(Debug)]
+pub struct TemplateCache {
+    /// Templates keyed by guild and lowercase name, matching the case insensitive names in the
+    /// database.
+    templates: HashMap<(u64, String), CachedTemplate>,
+    legacy_readers: HashSet<u64>,
+}
+
+impl TemplateCache {
+    pub fn load(db: &Connection) -> rusqlite::Result<TemplateCache> {
+        let mut substitutes: HashMap<i32, Vec<SubstituteRecord>> = HashMap::new();
+        let mut stmt = db.prepare(
+            "SELECT id, name, template_id, weight FROM substitutes WHERE weight > 0 ORDER BY id",
+        )?;
+        let records = stmt.query_map([], |row| {
+            Ok(SubstituteRecord {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                template_id: row.get(2)?,
+                weight: row.get(3)?,
+            })
+        })?;
+
+        for record in records {
+            let record = record?;
+            substitutes
+                .entry(record.template_id)
+                .or_default()
+                .push(record);
+        }
+
+        let mut templates = HashMap::new();
+        let mut stmt = db.prepare("SELECT id, name, guild_id, deck FROM templates")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i32>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u64>(2)?,
+                row.get::<_, bool>(3)?,
+            ))
+        })?;
+
+        for row in rows {
+            let (id, name, guild_id, deck) = row?;
+            templates.insert(
+                (guild_id, name.to_ascii_lowercase()),
+                CachedTemplate::new(id, deck, substitutes.remove(&id).unwrap_or_default()),
+            );
+        }
+
+        let mut stmt =
+            db.prepare("SELECT guild_id FROM guild_settings WHERE read_legacy_templates = 1")?;
+        let legacy_readers = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(TemplateCache {
+            templates,
+            legacy_readers,
+        })
+    }
+
+    /// Finds a template readable by the guild, preferring the guild's own template over a legacy
+    /// template of the same name.
+    pub fn find_template(&self, guild_id: u64, template: &str) -> Option<&CachedTemplate> {
+        let name = template.to_ascii_lowercase();
+
+        self.templates.get(&(guild_id, name.clone())).or_else(|| {
+            match self.legacy_readers.contains(&guild_id) {
+                true => self.templates.get(&(LEGACY_GUILD_ID, name)),
+                false => None,
+            }
+        })
+    }
+}
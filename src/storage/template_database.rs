@@ -1,36 +1,194 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use rand::Rng;
 pub use rusqlite;
-use rusqlite::{Connection, Transaction};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, ToSql, Transaction};
 
-use crate::text_interpolator;
+use super::template_cache::{CachedTemplate, TemplateCache};
+use super::template_export::{ExportedSubstitute, ExportedTemplate, ImportSummary, TemplateExport};
+use super::template_lint::{lint_templates, LintReport, LintTemplate};
+use super::template_references::rename_template_references;
 
-const DATABASE_VERSION: i32 = 1;
+const DATABASE_VERSION: i32 = 8;
 
-struct TemplateReplacement {
-    old: String,
-    new: String,
-}
+/// Namespace that holds every template created before templates were scoped by guild.
+pub const LEGACY_GUILD_ID: u64 = 0;
+/// User id of values stored by FSL code that are shared by everyone in a guild.
+pub const GUILD_VARIABLE_USER_ID: u64 = 0;
 
 #[derive(Debug)]
 pub struct FunboyDatabase {
     db: Connection,
+    /// Templates used for random selection, reloaded after being invalidated by a mutation.
+    cache: RefCell<Option<TemplateCache>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SubstituteRecord {
     pub id: i32,
     pub name: String,
     pub template_id: i32,
+    pub weight: u32,
+}
+
+#[derive(Debug)]
+pub struct TemplateInfo {
+    /// The user that created the template. Templates created before ownership was recorded have
+    /// no owner.
+    pub owner_id: Option<u64>,
+    pub locked: bool,
+}
+
+/// The part of a template that a change entry modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeField {
+    Template,
+    Substitute,
+    Weight,
+    Locked,
+    Deck,
+}
+
+impl ChangeField {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            ChangeField::Template => "template",
+            ChangeField::Substitute => "substitute",
+            ChangeField::Weight => "weight",
+            ChangeField::Locked => "locked",
+            ChangeField::Deck => "deck",
+        }
+    }
+}
+
+impl ToSql for ChangeField {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_str()))
+    }
+}
+
+impl FromSql for ChangeField {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "template" => Ok(ChangeField::Template),
+            "substitute" => Ok(ChangeField::Substitute),
+            "weight" => Ok(ChangeField::Weight),
+            "locked" => Ok(ChangeField::Locked),
+            "deck" => Ok(ChangeField::Deck),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Everything besides the name of a removed template or substitute, so undoing the removal
+/// restores it as it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemovedState {
+    /// The owner of a template or the user that added a substitute.
+    pub owner_id: Option<u64>,
+    pub locked: bool,
+    pub deck: bool,
+    pub weight: u32,
+}
+
+/// A single value modified by a change. A missing `before` means the value was created and a
+/// missing `after` means it was removed.
+#[derive(Debug, Clone)]
+pub struct ChangeEntry {
+    pub field: ChangeField,
+    pub substitute_id: Option<i64>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    /// Recorded for removals.
+    pub removed: Option<RemovedState>,
+}
+
+impl ChangeEntry {
+    fn template(before: Option<String>, after: Option<String>) -> ChangeEntry {
+        ChangeEntry {
+            field: ChangeField::Template,
+            substitute_id: None,
+            before,
+            after,
+            removed: None,
+        }
+    }
+
+    fn substitute(id: i64, before: Option<String>, after: Option<String>) -> ChangeEntry {
+        ChangeEntry {
+            field: ChangeField::Substitute,
+            substitute_id: Some(id),
+            before,
+            after,
+            removed: None,
+        }
+    }
+
+    /// A template setting toggled from `before` to `after`.
+    fn setting(field: ChangeField, before: bool, after: bool) -> ChangeEntry {
+        ChangeEntry {
+            field,
+            substitute_id: None,
+            before: Some(before.to_string()),
+            after: Some(after.to_string()),
+            removed: None,
+        }
+    }
+
+    fn removed(mut self, state: RemovedState) -> ChangeEntry {
+        self.removed = Some(state);
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct ChangeRecord {
+    pub id: i64,
+    pub user_id: u64,
+    /// Unix timestamp of when the change was made.
+    pub timestamp: i64,
+    pub action: String,
+    pub template: String,
+    pub undone: bool,
+    pub entries: Vec<ChangeEntry>,
+}
+
+/// A substitute rewritten because a template it references was renamed.
+#[derive(Debug)]
+pub struct RenamedReference {
+    pub template: String,
+    pub id: i64,
+    pub before: String,
+    pub after: String,
+}
+
+#[derive(Debug)]
+pub struct SubstituteMatch {
+    pub template: String,
+    pub id: i32,
+    pub name: String,
 }
 
 pub type UpdatedValues<'a> = Vec<&'a str>;
 
 impl FunboyDatabase {
+    /// Creates the latest schema for a new database.
+    ///
+    /// Upgrades create the schema of their own version instead so older databases pass through
+    /// every version in order.
     fn create_tables(db: &Connection) -> rusqlite::Result<()> {
         db.execute(
             "
             CREATE TABLE IF NOT EXISTS templates (
             id INTEGER PRIMARY KEY,
-            name TEXT NOT NULL UNIQUE COLLATE NOCASE
+            name TEXT NOT NULL COLLATE NOCASE,
+            guild_id INTEGER NOT NULL DEFAULT 0,
+            owner_id INTEGER,
+            locked INTEGER NOT NULL DEFAULT 0,
+            deck INTEGER NOT NULL DEFAULT 0,
+            UNIQUE(name, guild_id)
         )",
             [],
         )?;
@@ -41,14 +199,111 @@ impl FunboyDatabase {
             id INTEGER PRIMARY KEY,
             name TEXT NOT NULL COLLATE NOCASE,
             template_id INTEGER NOT NULL REFERENCES templates(id),
+            weight INTEGER NOT NULL DEFAULT 1,
+            added_by INTEGER,
             UNIQUE(name, template_id)
         )",
             [],
         )?;
 
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS guild_settings (
+            guild_id INTEGER PRIMARY KEY,
+            read_legacy_templates INTEGER NOT NULL DEFAULT 0
+        )",
+            [],
+        )?;
+
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS changes (
+            id INTEGER PRIMARY KEY,
+            guild_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+            action TEXT NOT NULL,
+            template TEXT NOT NULL COLLATE NOCASE,
+            undone INTEGER NOT NULL DEFAULT 0
+        )",
+            [],
+        )?;
+
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS change_entries (
+            id INTEGER PRIMARY KEY,
+            change_id INTEGER NOT NULL REFERENCES changes(id),
+            field TEXT NOT NULL,
+            substitute_id INTEGER,
+            before TEXT COLLATE NOCASE,
+            after TEXT COLLATE NOCASE,
+            owner_id INTEGER,
+            locked INTEGER,
+            deck INTEGER,
+            weight INTEGER
+        )",
+            [],
+        )?;
+
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS deck_draws (
+            channel_id INTEGER NOT NULL,
+            template_id INTEGER NOT NULL REFERENCES templates(id),
+            substitute_id INTEGER NOT NULL REFERENCES substitutes(id),
+            draws INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY(channel_id, substitute_id)
+        )",
+            [],
+        )?;
+
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS stored_variables (
+            guild_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            value TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            PRIMARY KEY(guild_id, user_id, name)
+        )",
+            [],
+        )?;
+
+        Self::create_search_index(db)?;
+
         Ok(())
     }
 
+    /// Creates a full-text index over substitute names kept up to date by triggers.
+    fn create_search_index(db: &Connection) -> rusqlite::Result<()> {
+        db.execute_batch(
+            "
+            CREATE VIRTUAL TABLE IF NOT EXISTS substitutes_fts USING fts5(
+                name,
+                content = 'substitutes',
+                content_rowid = 'id'
+            );
+
+            CREATE TRIGGER IF NOT EXISTS substitutes_fts_insert AFTER INSERT ON substitutes BEGIN
+                INSERT INTO substitutes_fts (rowid, name) VALUES (new.id, new.name);
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS substitutes_fts_delete AFTER DELETE ON substitutes BEGIN
+                INSERT INTO substitutes_fts (substitutes_fts, rowid, name)
+                VALUES ('delete', old.id, old.name);
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS substitutes_fts_update AFTER UPDATE ON substitutes BEGIN
+                INSERT INTO substitutes_fts (substitutes_fts, rowid, name)
+                VALUES ('delete', old.id, old.name);
+                INSERT INTO substitutes_fts (rowid, name) VALUES (new.id, new.name);
+            END;
+            ",
+        )
+    }
+
     fn initialize_db(db: &Connection) -> rusqlite::Result<()> {
         let mut stmt =
             db.prepare("SELECT name FROM sqlite_master WHERE type='table' AND name='templates'")?;
@@ -56,9 +311,29 @@ impl FunboyDatabase {
         if stmt.query([])?.next()?.is_some() {
             let version = Self::get_schema_version(db)?;
 
-            match version {
-                0 => Self::upgrade_to_version_1(db)?,
-                _ => {}
+            if version < 1 {
+                Self::upgrade_to_version_1(db)?;
+            }
+            if version < 2 {
+                Self::upgrade_to_version_2(db)?;
+            }
+            if version < 3 {
+                Self::upgrade_to_version_3(db)?;
+            }
+            if version < 4 {
+                Self::upgrade_to_version_4(db)?;
+            }
+            if version < 5 {
+                Self::upgrade_to_version_5(db)?;
+            }
+            if version < 6 {
+                Self::upgrade_to_version_6(db)?;
+            }
+            if version < 7 {
+                Self::upgrade_to_version_7(db)?;
+            }
+            if version < 8 {
+                Self::upgrade_to_version_8(db)?;
             }
         } else {
             Self::set_schema_version(db, DATABASE_VERSION)?;
@@ -87,6 +362,31 @@ impl FunboyDatabase {
         Ok(())
     }
 
+    fn has_column(db: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+        let count: i64 = db.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+            [table, column],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Adds a column unless an earlier upgrade already recreated the table with it.
+    fn add_column(
+        db: &Connection,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> rusqlite::Result<()> {
+        if !Self::has_column(db, table, column)? {
+            db.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
     fn create_backup_tables(db: &Connection) -> rusqlite::Result<()> {
         db.execute(
             "CREATE TABLE templates_backup AS SELECT * FROM templates",
@@ -133,7 +433,21 @@ impl FunboyDatabase {
         Self::ignore_foreign_keys(db)?;
         Self::create_backup_tables(db)?;
         Self::drop_tables(db)?;
-        Self::create_tables(db)?;
+        db.execute_batch(
+            "
+            CREATE TABLE templates (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE
+            );
+
+            CREATE TABLE substitutes (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL COLLATE NOCASE,
+            template_id INTEGER NOT NULL REFERENCES templates(id),
+            UNIQUE(name, template_id)
+            );
+            ",
+        )?;
         Self::populate_tables(db)?;
         Self::drop_backups(db)?;
         Self::acknowledge_foreign_keys(db)?;
@@ -141,60 +455,445 @@ impl FunboyDatabase {
         Ok(())
     }
 
+    /// Scopes templates by guild. Every existing template is moved into the legacy namespace.
+    fn upgrade_to_version_2(db: &Connection) -> rusqlite::Result<()> {
+        Self::ignore_foreign_keys(db)?;
+        Self::create_backup_tables(db)?;
+        Self::drop_tables(db)?;
+        db.execute_batch(
+            "
+            CREATE TABLE templates (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL COLLATE NOCASE,
+            guild_id INTEGER NOT NULL DEFAULT 0,
+            UNIQUE(name, guild_id)
+            );
+
+            CREATE TABLE substitutes (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL COLLATE NOCASE,
+            template_id INTEGER NOT NULL REFERENCES templates(id),
+            UNIQUE(name, template_id)
+            );
+
+            CREATE TABLE IF NOT EXISTS guild_settings (
+            guild_id INTEGER PRIMARY KEY,
+            read_legacy_templates INTEGER NOT NULL DEFAULT 0
+            );
+            ",
+        )?;
+        Self::populate_tables(db)?;
+        Self::drop_backups(db)?;
+        Self::acknowledge_foreign_keys(db)?;
+        Self::set_schema_version(db, 2)?;
+        Ok(())
+    }
+
+    /// Adds a weight to substitutes so some can be picked more often than others.
+    fn upgrade_to_version_3(db: &Connection) -> rusqlite::Result<()> {
+        Self::add_column(db, "substitutes", "weight", "INTEGER NOT NULL DEFAULT 1")?;
+        Self::set_schema_version(db, 3)?;
+        Ok(())
+    }
+
+    /// Records who created templates and added substitutes so templates can be locked by owners.
+    fn upgrade_to_version_4(db: &Connection) -> rusqlite::Result<()> {
+        Self::add_column(db, "templates", "owner_id", "INTEGER")?;
+        Self::add_column(db, "templates", "locked", "INTEGER NOT NULL DEFAULT 0")?;
+        Self::add_column(db, "substitutes", "added_by", "INTEGER")?;
+        Self::set_schema_version(db, 4)?;
+        Ok(())
+    }
+
+    /// Adds the change history used to review and undo template edits.
+    fn upgrade_to_version_5(db: &Connection) -> rusqlite::Result<()> {
+        db.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS changes (
+            id INTEGER PRIMARY KEY,
+            guild_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+            action TEXT NOT NULL,
+            template TEXT NOT NULL COLLATE NOCASE,
+            undone INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE TABLE IF NOT EXISTS change_entries (
+            id INTEGER PRIMARY KEY,
+            change_id INTEGER NOT NULL REFERENCES changes(id),
+            field TEXT NOT NULL,
+            substitute_id INTEGER,
+            before TEXT COLLATE NOCASE,
+            after TEXT COLLATE NOCASE,
+            owner_id INTEGER,
+            locked INTEGER,
+            weight INTEGER
+            );
+            ",
+        )?;
+        Self::set_schema_version(db, 5)?;
+        Ok(())
+    }
+
+    /// Indexes every existing substitute for full-text search.
+    fn upgrade_to_version_6(db: &Connection) -> rusqlite::Result<()> {
+        Self::create_search_index(db)?;
+        db.execute(
+            "INSERT INTO substitutes_fts (substitutes_fts) VALUES ('rebuild')",
+            [],
+        )?;
+        Self::set_schema_version(db, 6)?;
+        Ok(())
+    }
+
+    /// Adds deck mode along with the substitutes drawn from each deck.
+    fn upgrade_to_version_7(db: &Connection) -> rusqlite::Result<()> {
+        Self::add_column(db, "templates", "deck", "INTEGER NOT NULL DEFAULT 0")?;
+        Self::add_column(db, "change_entries", "deck", "INTEGER")?;
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS deck_draws (
+            channel_id INTEGER NOT NULL,
+            template_id INTEGER NOT NULL REFERENCES templates(id),
+            substitute_id INTEGER NOT NULL REFERENCES substitutes(id),
+            draws INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY(channel_id, substitute_id)
+        )",
+            [],
+        )?;
+        Self::set_schema_version(db, 7)?;
+        Ok(())
+    }
+
+    /// Adds values stored by FSL code that persist between generations.
+    fn upgrade_to_version_8(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS stored_variables (
+            guild_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            value TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            PRIMARY KEY(guild_id, user_id, name)
+        )",
+            [],
+        )?;
+        Self::set_schema_version(db, 8)?;
+        Ok(())
+    }
+
     pub fn from_path(path: &str) -> rusqlite::Result<FunboyDatabase> {
         let db = Connection::open(path)?;
 
         Self::initialize_db(&db)?;
+        let cache = TemplateCache::load(&db)?;
+
+        Ok(FunboyDatabase {
+            db,
+            cache: RefCell::new(Some(cache)),
+        })
+    }
+
+    /// Drops the cached templates so they are reloaded the next time they are read.
+    ///
+    /// Must be called by every method that modifies templates, substitutes or legacy access.
+    fn invalidate_cache(&self) {
+        self.cache.replace(None);
+    }
+
+    /// Picks a random substitute from the cached template, loading the cache if it was
+    /// invalidated.
+    fn choose_cached_sub(
+        &mut self,
+        guild_id: u64,
+        channel_id: u64,
+        template: &str,
+        rng: &mut impl Rng,
+    ) -> rusqlite::Result<Option<SubstituteRecord>> {
+        if self.cache.borrow().is_none() {
+            self.cache.replace(Some(TemplateCache::load(&self.db)?));
+        }
+
+        let cache = self.cache.borrow();
+        let template = cache
+            .as_ref()
+            .and_then(|cache| cache.find_template(guild_id, template))
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+
+        if template.is_deck() {
+            let tx = self.db.transaction()?;
+            let record = Self::execute_draw_from_deck(&tx, channel_id, template, rng)?;
+            tx.commit()?;
+            Ok(record)
+        } else {
+            Ok(template.choose(rng).cloned())
+        }
+    }
+
+    /// Draws a substitute the channel hasn't drawn as many times as its weight, shuffling the
+    /// deck once every substitute has been drawn.
+    fn execute_draw_from_deck(
+        tx: &Transaction,
+        channel_id: u64,
+        template: &CachedTemplate,
+        rng: &mut impl Rng,
+    ) -> rusqlite::Result<Option<SubstituteRecord>> {
+        let drawn: HashMap<i32, u32> = tx
+            .prepare(
+                "SELECT substitute_id, draws FROM deck_draws
+                 WHERE channel_id = ?1 AND template_id = ?2",
+            )?
+            .query_map(params![channel_id, template.id()], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let record = match template.draw(&drawn, rng) {
+            Some(record) => Some(record),
+            None => {
+                tx.execute(
+                    "DELETE FROM deck_draws WHERE channel_id = ?1 AND template_id = ?2",
+                    params![channel_id, template.id()],
+                )?;
+                template.choose(rng)
+            }
+        };
+
+        if let Some(record) = record {
+            tx.execute(
+                "INSERT INTO deck_draws (channel_id, template_id, substitute_id, draws)
+                 VALUES (?1, ?2, ?3, 1)
+                 ON CONFLICT(channel_id, substitute_id) DO UPDATE SET draws = draws + 1",
+                params![channel_id, template.id(), record.id],
+            )?;
+        }
 
-        Ok(FunboyDatabase { db })
+        Ok(record.cloned())
     }
 
     fn find_template_id_with_transaction(
         tx: &Transaction,
+        guild_id: u64,
         template: &str,
     ) -> rusqlite::Result<String> {
-        let mut stmt = tx.prepare("SELECT id FROM templates WHERE name = ?1")?;
-        let template_id: i64 = stmt.query_row(&[template], |row| row.get(0))?;
+        let mut stmt = tx.prepare("SELECT id FROM templates WHERE name = ?1 AND guild_id = ?2")?;
+        let template_id: i64 = stmt.query_row(params![template, guild_id], |row| row.get(0))?;
         Ok(template_id.to_string())
     }
 
-    pub fn insert_sub(&mut self, template: &str, substitute: &str) -> rusqlite::Result<bool> {
+    /// Records a change and its entries in the change history.
+    ///
+    /// Nothing is recorded if the change has no entries.
+    fn execute_record_change(
+        tx: &Transaction,
+        guild_id: u64,
+        user_id: u64,
+        action: &str,
+        template: &str,
+        entries: &[ChangeEntry],
+    ) -> rusqlite::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        tx.execute(
+            "INSERT INTO changes (guild_id, user_id, action, template) VALUES (?1, ?2, ?3, ?4)",
+            params![guild_id, user_id, action, template],
+        )?;
+        let change_id = tx.last_insert_rowid();
+
+        for entry in entries {
+            let removed = entry.removed.as_ref();
+
+            tx.execute(
+                "INSERT INTO change_entries
+                 (change_id, field, substitute_id, before, after, owner_id, locked, deck, weight)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    change_id,
+                    entry.field,
+                    entry.substitute_id,
+                    entry.before,
+                    entry.after,
+                    removed.and_then(|state| state.owner_id),
+                    removed.map(|state| state.locked),
+                    removed.map(|state| state.deck),
+                    removed.map(|state| state.weight)
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn find_sub_with_transaction(
+        tx: &Transaction,
+        template_id: &str,
+        substitute: &str,
+    ) -> rusqlite::Result<Option<(i64, String)>> {
+        let mut stmt =
+            tx.prepare("SELECT id, name FROM substitutes WHERE template_id = ?1 AND name = ?2")?;
+        let mut rows = stmt.query([template_id, substitute])?;
+
+        match rows.next()? {
+            Some(row) => Ok(Some((row.get(0)?, row.get(1)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn find_sub_by_id_with_transaction(
+        tx: &Transaction,
+        template_id: &str,
+        id: usize,
+    ) -> rusqlite::Result<Option<(i64, String)>> {
+        let mut stmt =
+            tx.prepare("SELECT id, name FROM substitutes WHERE template_id = ?1 AND id = ?2")?;
+        let mut rows = stmt.query([template_id, &id.to_string()])?;
+
+        match rows.next()? {
+            Some(row) => Ok(Some((row.get(0)?, row.get(1)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn execute_delete_sub(
+        tx: &Transaction,
+        id: i64,
+        name: String,
+        entries: &mut Vec<ChangeEntry>,
+    ) -> rusqlite::Result<bool> {
+        let state = tx
+            .query_row(
+                "SELECT added_by, weight FROM substitutes WHERE id = ?1",
+                [id],
+                |row| {
+                    Ok(RemovedState {
+                        owner_id: row.get(0)?,
+                        locked: false,
+                        deck: false,
+                        weight: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Self::execute_forget_sub_draws(tx, id)?;
+        let result = tx.execute("DELETE FROM substitutes WHERE id = ?1", [id])?;
+
+        if let (true, Some(state)) = (result > 0, state) {
+            entries.push(ChangeEntry::substitute(id, Some(name), None).removed(state));
+        }
+
+        Ok(result > 0)
+    }
+
+    /// Forgets every channel's draws of a substitute, so a removed or rewritten substitute isn't
+    /// counted as drawn.
+    fn execute_forget_sub_draws(tx: &Transaction, id: i64) -> rusqlite::Result<()> {
+        tx.execute("DELETE FROM deck_draws WHERE substitute_id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn execute_update_sub(
+        tx: &Transaction,
+        id: i64,
+        old_name: String,
+        new_name: &str,
+        entries: &mut Vec<ChangeEntry>,
+    ) -> rusqlite::Result<bool> {
+        Self::execute_forget_sub_draws(tx, id)?;
+        let result = tx.execute(
+            "UPDATE substitutes SET name = ?1 WHERE id = ?2",
+            params![new_name, id],
+        )?;
+
+        if result > 0 {
+            entries.push(ChangeEntry::substitute(
+                id,
+                Some(old_name),
+                Some(new_name.to_string()),
+            ));
+        }
+
+        Ok(result > 0)
+    }
+
+    pub fn insert_sub(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        template: &str,
+        substitute: &str,
+    ) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
         let tx = self.db.transaction()?;
-        Self::execute_insert_template(&tx, template)?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+        let mut entries = Vec::new();
+        Self::execute_insert_template(&tx, guild_id, user_id, template, &mut entries)?;
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
         let result = tx.execute(
-            "INSERT OR IGNORE INTO substitutes (name, template_id) VALUES (?1, ?2)",
-            [substitute.to_string(), template_id.to_string()],
+            "INSERT OR IGNORE INTO substitutes (name, template_id, added_by) VALUES (?1, ?2, ?3)",
+            params![substitute, template_id, user_id],
         )?;
 
+        if result > 0 {
+            entries.push(ChangeEntry::substitute(
+                tx.last_insert_rowid(),
+                None,
+                Some(substitute.to_string()),
+            ));
+        }
+
+        Self::execute_record_change(&tx, guild_id, user_id, "insert_sub", template, &entries)?;
+
         tx.commit()?;
 
         Ok(result > 0)
     }
 
-    fn execute_insert_template(tx: &Transaction, template: &str) -> rusqlite::Result<()> {
-        tx.execute(
-            "INSERT OR IGNORE INTO templates (name) VALUES (?1)",
-            &[template],
+    fn execute_insert_template(
+        tx: &Transaction,
+        guild_id: u64,
+        user_id: u64,
+        template: &str,
+        entries: &mut Vec<ChangeEntry>,
+    ) -> rusqlite::Result<()> {
+        let result = tx.execute(
+            "INSERT OR IGNORE INTO templates (name, guild_id, owner_id) VALUES (?1, ?2, ?3)",
+            params![template, guild_id, user_id],
         )?;
+
+        if result > 0 {
+            entries.push(ChangeEntry::template(None, Some(template.to_string())));
+        }
+
         Ok(())
     }
 
     fn execute_insert_subs<'a>(
         tx: &Transaction,
+        guild_id: u64,
+        user_id: u64,
         template: &str,
         substitutes: &[&'a str],
+        entries: &mut Vec<ChangeEntry>,
     ) -> rusqlite::Result<UpdatedValues<'a>> {
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
         let mut inserted_subs = UpdatedValues::new();
 
         for sub in substitutes {
             let result = tx.execute(
-                "INSERT OR IGNORE INTO substitutes (name, template_id) VALUES (?1, ?2)",
-                &[*sub, &template_id],
+                "INSERT OR IGNORE INTO substitutes (name, template_id, added_by) VALUES (?1, ?2, ?3)",
+                params![*sub, template_id, user_id],
             )?;
             if result > 0 {
                 inserted_subs.push(*sub);
+                entries.push(ChangeEntry::substitute(
+                    tx.last_insert_rowid(),
+                    None,
+                    Some(sub.to_string()),
+                ));
             }
         }
 
@@ -203,88 +902,176 @@ impl FunboyDatabase {
 
     pub fn insert_subs<'a>(
         &mut self,
+        guild_id: u64,
+        user_id: u64,
         template: &str,
         substitutes: Option<&[&'a str]>,
     ) -> rusqlite::Result<UpdatedValues<'a>> {
+        self.invalidate_cache();
         let mut change_log = UpdatedValues::new();
+        let mut entries = Vec::new();
 
         let tx = self.db.transaction()?;
 
-        Self::execute_insert_template(&tx, template)?;
+        Self::execute_insert_template(&tx, guild_id, user_id, template, &mut entries)?;
 
         if let Some(subs) = substitutes {
-            change_log = Self::execute_insert_subs(&tx, template, subs)?;
+            change_log =
+                Self::execute_insert_subs(&tx, guild_id, user_id, template, subs, &mut entries)?;
         }
 
+        Self::execute_record_change(&tx, guild_id, user_id, "insert_subs", template, &entries)?;
+
         tx.commit()?;
 
         Ok(change_log)
     }
 
-    pub fn remove_template(&mut self, template: &str) -> rusqlite::Result<bool> {
+    pub fn remove_template(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        template: &str,
+    ) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
         let tx = self.db.transaction()?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
+        let mut entries = Vec::new();
 
-        tx.execute(
-            "DELETE FROM substitutes WHERE template_id = ?1",
-            [&template_id],
-        )?;
+        let subs: Vec<(i64, String)> = tx
+            .prepare("SELECT id, name FROM substitutes WHERE template_id = ?1")?
+            .query_map([&template_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
 
-        let result = tx.execute("DELETE FROM templates WHERE id = ?1", [&template_id])?;
+        for (id, name) in subs {
+            Self::execute_delete_sub(&tx, id, name, &mut entries)?;
+        }
+
+        let state = tx.query_row(
+            "SELECT owner_id, locked, deck FROM templates WHERE id = ?1",
+            [&template_id],
+            |row| {
+                Ok(RemovedState {
+                    owner_id: row.get(0)?,
+                    locked: row.get(1)?,
+                    deck: row.get(2)?,
+                    weight: 0,
+                })
+            },
+        )?;
+
+        tx.execute(
+            "DELETE FROM deck_draws WHERE template_id = ?1",
+            [&template_id],
+        )?;
+        let result = tx.execute("DELETE FROM templates WHERE id = ?1", [&template_id])?;
+
+        if result > 0 {
+            entries.push(ChangeEntry::template(Some(template.to_string()), None).removed(state));
+        }
+
+        Self::execute_record_change(
+            &tx,
+            guild_id,
+            user_id,
+            "remove_template",
+            template,
+            &entries,
+        )?;
 
         tx.commit()?;
 
         Ok(result > 0)
     }
 
-    pub fn remove_sub(&mut self, template: &str, substitute: &str) -> rusqlite::Result<bool> {
+    pub fn remove_sub(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        template: &str,
+        substitute: &str,
+    ) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
         let tx = self.db.transaction()?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
+        let mut entries = Vec::new();
 
-        let result = tx.execute(
-            "DELETE FROM substitutes WHERE template_id = ?1 AND name = ?2",
-            &[&template_id, substitute],
-        )?;
+        let result = match Self::find_sub_with_transaction(&tx, &template_id, substitute)? {
+            Some((id, name)) => Self::execute_delete_sub(&tx, id, name, &mut entries)?,
+            None => false,
+        };
+
+        Self::execute_record_change(&tx, guild_id, user_id, "remove_sub", template, &entries)?;
 
         tx.commit()?;
 
-        Ok(result > 0)
+        Ok(result)
     }
 
-    pub fn remove_sub_by_id(&mut self, template: &str, id: usize) -> rusqlite::Result<bool> {
+    pub fn remove_sub_by_id(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        template: &str,
+        id: usize,
+    ) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
         let tx = self.db.transaction()?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
-
-        let result = tx.execute(
-            "DELETE FROM substitutes WHERE id = ?1 AND template_id = ?2",
-            &[&id.to_string(), &template_id],
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
+        let mut entries = Vec::new();
+
+        let result = match Self::find_sub_by_id_with_transaction(&tx, &template_id, id)? {
+            Some((id, name)) => Self::execute_delete_sub(&tx, id, name, &mut entries)?,
+            None => false,
+        };
+
+        Self::execute_record_change(
+            &tx,
+            guild_id,
+            user_id,
+            "remove_sub_by_id",
+            template,
+            &entries,
         )?;
 
         tx.commit()?;
 
-        Ok(result > 0)
+        Ok(result)
     }
 
     pub fn remove_subs_by_id(
         &mut self,
+        guild_id: u64,
+        user_id: u64,
         template: &str,
         ids: &[usize],
     ) -> rusqlite::Result<Vec<usize>> {
+        self.invalidate_cache();
         let tx = self.db.transaction()?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
+        let mut entries = Vec::new();
 
         let mut removed_ids: Vec<usize> = Vec::new();
 
         for id in ids {
-            let result = tx.execute(
-                "DELETE FROM substitutes WHERE id = ?1 AND template_id = ?2",
-                &[&id.to_string(), &template_id],
-            )?;
-            if result > 0 {
-                removed_ids.push(*id);
+            if let Some((sub_id, name)) =
+                Self::find_sub_by_id_with_transaction(&tx, &template_id, *id)?
+            {
+                if Self::execute_delete_sub(&tx, sub_id, name, &mut entries)? {
+                    removed_ids.push(*id);
+                }
             }
         }
 
+        Self::execute_record_change(
+            &tx,
+            guild_id,
+            user_id,
+            "remove_subs_by_id",
+            template,
+            &entries,
+        )?;
+
         tx.commit()?;
 
         Ok(removed_ids)
@@ -292,135 +1079,846 @@ impl FunboyDatabase {
 
     pub fn remove_subs<'a>(
         &mut self,
+        guild_id: u64,
+        user_id: u64,
         template: &str,
         substitutes: &[&'a str],
     ) -> rusqlite::Result<UpdatedValues<'a>> {
+        self.invalidate_cache();
         let tx = self.db.transaction()?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
+        let mut entries = Vec::new();
 
         let mut removed_subs = UpdatedValues::new();
 
         for sub in substitutes {
-            let result = tx.execute(
-                "DELETE FROM substitutes WHERE template_id = ?1 AND name = ?2",
-                &[&template_id, *sub],
-            )?;
-            if result > 0 {
-                removed_subs.push(*sub);
+            if let Some((id, name)) = Self::find_sub_with_transaction(&tx, &template_id, sub)? {
+                if Self::execute_delete_sub(&tx, id, name, &mut entries)? {
+                    removed_subs.push(*sub);
+                }
             }
         }
 
+        Self::execute_record_change(&tx, guild_id, user_id, "remove_subs", template, &entries)?;
+
         tx.commit()?;
 
         Ok(removed_subs)
     }
 
-    fn get_template_replacements(old: &str, new: &str) -> Vec<TemplateReplacement> {
-        let mut replacements = Vec::new();
-        for header in text_interpolator::defaults::TEMPLATE_HEADERS {
-            let occurence = header.to_string() + old;
-            let replacement = header.to_string() + new;
-            replacements.push(TemplateReplacement {
-                old: occurence,
-                new: replacement,
-            });
-        }
-
-        let occurence = "get_sub(\"".to_string() + old + "\")";
-        let replacement = "get_sub(\"".to_string() + new + "\")";
-        replacements.push(TemplateReplacement {
-            old: occurence,
-            new: replacement,
-        });
+    /// Finds every substitute in the guild that references the old template and how it would be
+    /// rewritten to reference the new template.
+    fn find_renamed_references(
+        db: &Connection,
+        guild_id: u64,
+        old_template: &str,
+        new_template: &str,
+    ) -> rusqlite::Result<Vec<RenamedReference>> {
+        let mut stmt = db.prepare(
+            "SELECT substitutes.id, substitutes.name, templates.name
+             FROM substitutes
+             JOIN templates ON substitutes.template_id = templates.id
+             WHERE INSTR(LOWER(substitutes.name), LOWER(?1)) > 0 AND templates.guild_id = ?2
+             ORDER BY LOWER(templates.name), substitutes.id",
+        )?;
+        let subs = stmt
+            .query_map(params![old_template, guild_id], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(i64, String, String)>>>()?;
+
+        Ok(subs
+            .into_iter()
+            .filter_map(|(id, before, template)| {
+                let after = rename_template_references(&before, old_template, new_template);
+
+                (after != before).then_some(RenamedReference {
+                    template,
+                    id,
+                    before,
+                    after,
+                })
+            })
+            .collect())
+    }
 
-        replacements
+    /// Lists the substitutes that renaming the template would rewrite without changing anything.
+    pub fn preview_rename_template(
+        &self,
+        guild_id: u64,
+        old_template: &str,
+        new_template: &str,
+    ) -> rusqlite::Result<Vec<RenamedReference>> {
+        Self::find_renamed_references(&self.db, guild_id, old_template, new_template)
     }
 
     pub fn rename_template(
         &mut self,
+        guild_id: u64,
+        user_id: u64,
         old_template: &str,
         new_template: &str,
     ) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
         let tx = self.db.transaction()?;
+        let mut entries = Vec::new();
 
         let result = tx.execute(
-            "UPDATE templates SET name = ?1 WHERE name = ?2",
-            &[new_template, old_template],
+            "UPDATE templates SET name = ?1 WHERE name = ?2 AND guild_id = ?3",
+            params![new_template, old_template, guild_id],
         )?;
 
-        for replacement in Self::get_template_replacements(old_template, new_template) {
-            tx.execute(
-                "UPDATE substitutes 
-                    SET name = REPLACE(name, ?1, ?2)
-                    WHERE INSTR(name, ?1) > 0;",
-                [replacement.old, replacement.new],
+        // References to a template that doesn't exist are left alone
+        if result == 0 {
+            return Ok(false);
+        }
+
+        entries.push(ChangeEntry::template(
+            Some(old_template.to_string()),
+            Some(new_template.to_string()),
+        ));
+
+        for reference in Self::find_renamed_references(&tx, guild_id, old_template, new_template)? {
+            Self::execute_update_sub(
+                &tx,
+                reference.id,
+                reference.before,
+                &reference.after,
+                &mut entries,
             )?;
         }
 
+        Self::execute_record_change(
+            &tx,
+            guild_id,
+            user_id,
+            "rename_template",
+            new_template,
+            &entries,
+        )?;
+
         tx.commit()?;
 
-        Ok(result > 0)
+        Ok(true)
     }
 
     pub fn replace_substitute(
         &mut self,
+        guild_id: u64,
+        user_id: u64,
         template: &str,
         old_sub: &str,
         new_sub: &str,
     ) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
         let tx = self.db.transaction()?;
 
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
-
-        let result = tx.execute(
-            "UPDATE substitutes SET name = ?1 WHERE name = ?2 AND template_id = ?3",
-            &[new_sub, old_sub, &template_id],
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
+        let mut entries = Vec::new();
+
+        let result = match Self::find_sub_with_transaction(&tx, &template_id, old_sub)? {
+            Some((id, name)) => Self::execute_update_sub(&tx, id, name, new_sub, &mut entries)?,
+            None => false,
+        };
+
+        Self::execute_record_change(
+            &tx,
+            guild_id,
+            user_id,
+            "replace_substitute",
+            template,
+            &entries,
         )?;
 
         tx.commit()?;
 
-        Ok(result > 0)
+        Ok(result)
     }
 
     pub fn replace_substitute_by_id(
         &mut self,
+        guild_id: u64,
+        user_id: u64,
         template: &str,
         id: usize,
         new_sub: &str,
+    ) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
+        let tx = self.db.transaction()?;
+
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
+        let mut entries = Vec::new();
+
+        let result = match Self::find_sub_by_id_with_transaction(&tx, &template_id, id)? {
+            Some((id, name)) => Self::execute_update_sub(&tx, id, name, new_sub, &mut entries)?,
+            None => false,
+        };
+
+        Self::execute_record_change(
+            &tx,
+            guild_id,
+            user_id,
+            "replace_substitute_by_id",
+            template,
+            &entries,
+        )?;
+
+        tx.commit()?;
+
+        Ok(result)
+    }
+
+    pub fn set_sub_weight(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        template: &str,
+        id: usize,
+        weight: u32,
+    ) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
+        let tx = self.db.transaction()?;
+
+        let template_id = Self::find_template_id_with_transaction(&tx, guild_id, template)?;
+        let mut entries = Vec::new();
+
+        let old_weight: Option<u32> = tx
+            .query_row(
+                "SELECT weight FROM substitutes WHERE id = ?1 AND template_id = ?2",
+                params![id, template_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let result = tx.execute(
+            "UPDATE substitutes SET weight = ?1 WHERE id = ?2 AND template_id = ?3",
+            params![weight, id, template_id],
+        )?;
+
+        if let Some(old_weight) = old_weight {
+            entries.push(ChangeEntry {
+                field: ChangeField::Weight,
+                substitute_id: Some(id as i64),
+                before: Some(old_weight.to_string()),
+                after: Some(weight.to_string()),
+                removed: None,
+            });
+        }
+
+        Self::execute_record_change(&tx, guild_id, user_id, "set_sub_weight", template, &entries)?;
+
+        tx.commit()?;
+
+        Ok(result > 0)
+    }
+
+    fn get_change_entries(&self, change_id: i64) -> rusqlite::Result<Vec<ChangeEntry>> {
+        let mut stmt = self.db.prepare(
+            "SELECT field, substitute_id, before, after, owner_id, locked, deck, weight
+             FROM change_entries
+             WHERE change_id = ?1
+             ORDER BY id ASC",
+        )?;
+
+        let entries = stmt
+            .query_map([change_id], |row| {
+                let removed = match row.get::<_, Option<u32>>(7)? {
+                    Some(weight) => Some(RemovedState {
+                        owner_id: row.get(4)?,
+                        locked: row.get(5)?,
+                        deck: row.get(6)?,
+                        weight,
+                    }),
+                    None => None,
+                };
+
+                Ok(ChangeEntry {
+                    field: row.get(0)?,
+                    substitute_id: row.get(1)?,
+                    before: row.get(2)?,
+                    after: row.get(3)?,
+                    removed,
+                })
+            })?
+            .collect();
+
+        entries
+    }
+
+    fn get_changes(
+        &self,
+        query: &str,
+        params: impl rusqlite::Params,
+    ) -> rusqlite::Result<Vec<ChangeRecord>> {
+        let mut stmt = self.db.prepare(query)?;
+
+        let changes: Vec<ChangeRecord> = stmt
+            .query_map(params, |row| {
+                Ok(ChangeRecord {
+                    id: row.get(0)?,
+                    user_id: row.get(1)?,
+                    timestamp: row.get(2)?,
+                    action: row.get(3)?,
+                    template: row.get(4)?,
+                    undone: row.get(5)?,
+                    entries: Vec::new(),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        changes
+            .into_iter()
+            .map(|mut change| {
+                change.entries = self.get_change_entries(change.id)?;
+                Ok(change)
+            })
+            .collect()
+    }
+
+    /// Gets the most recent changes to a template, including changes made under previous names.
+    pub fn get_history(
+        &self,
+        guild_id: u64,
+        template: &str,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<ChangeRecord>> {
+        self.get_changes(
+            "SELECT id, user_id, timestamp, action, template, undone
+             FROM changes
+             WHERE guild_id = ?1
+             AND (template = ?2 OR id IN (
+                SELECT change_id FROM change_entries
+                WHERE field = 'template' AND (before = ?2 OR after = ?2)
+             ) OR id IN (
+                SELECT change_entries.change_id FROM change_entries
+                JOIN substitutes ON change_entries.substitute_id = substitutes.id
+                JOIN templates ON substitutes.template_id = templates.id
+                WHERE templates.name = ?2 AND templates.guild_id = ?1
+             ))
+             ORDER BY id DESC
+             LIMIT ?3",
+            params![guild_id, template, limit],
+        )
+    }
+
+    /// Gets the most recent change made by a user that has not been undone.
+    pub fn get_last_change(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+    ) -> rusqlite::Result<Option<ChangeRecord>> {
+        Ok(self
+            .get_changes(
+                "SELECT id, user_id, timestamp, action, template, undone
+                 FROM changes
+                 WHERE guild_id = ?1 AND user_id = ?2 AND undone = 0
+                 ORDER BY id DESC
+                 LIMIT 1",
+                params![guild_id, user_id],
+            )?
+            .pop())
+    }
+
+    /// Lists the templates undoing a change would modify, including the templates of substitutes
+    /// a rename rewrote.
+    pub fn get_change_templates(
+        &self,
+        guild_id: u64,
+        change: &ChangeRecord,
+    ) -> rusqlite::Result<Vec<String>> {
+        let mut templates = vec![change.template.clone()];
+        let mut stmt = self.db.prepare(
+            "SELECT templates.name
+             FROM substitutes
+             JOIN templates ON substitutes.template_id = templates.id
+             WHERE substitutes.id = ?1 AND templates.guild_id = ?2",
+        )?;
+
+        for entry in &change.entries {
+            let template = match (entry.field, entry.substitute_id) {
+                (ChangeField::Template, _) => entry.after.clone().or(entry.before.clone()),
+                (_, Some(id)) => stmt
+                    .query_row(params![id, guild_id], |row| row.get(0))
+                    .optional()?,
+                _ => None,
+            };
+
+            if let Some(template) = template {
+                if !templates
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&template))
+                {
+                    templates.push(template);
+                }
+            }
+        }
+
+        Ok(templates)
+    }
+
+    /// Reverts every entry of a change in reverse order.
+    ///
+    /// Entries that no longer apply, such as a substitute that has since been edited again, are
+    /// skipped.
+    pub fn undo_change(&mut self, guild_id: u64, change: &ChangeRecord) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
+        let tx = self.db.transaction()?;
+
+        let result = tx.execute(
+            "UPDATE changes SET undone = 1 WHERE id = ?1 AND guild_id = ?2 AND undone = 0",
+            params![change.id, guild_id],
+        )?;
+
+        if result == 0 {
+            return Ok(false);
+        }
+
+        for entry in change.entries.iter().rev() {
+            match (&entry.field, &entry.before, &entry.after) {
+                (ChangeField::Template, None, Some(created)) => {
+                    tx.execute(
+                        "DELETE FROM templates
+                         WHERE name = ?1 AND guild_id = ?2
+                         AND NOT EXISTS (
+                            SELECT 1 FROM substitutes WHERE template_id = templates.id
+                         )",
+                        params![created, guild_id],
+                    )?;
+                }
+                (ChangeField::Template, Some(removed), None) => {
+                    let state = entry.removed.as_ref();
+
+                    tx.execute(
+                        "INSERT OR IGNORE INTO templates (name, guild_id, owner_id, locked, deck)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![
+                            removed,
+                            guild_id,
+                            state.and_then(|state| state.owner_id),
+                            state.is_some_and(|state| state.locked),
+                            state.is_some_and(|state| state.deck)
+                        ],
+                    )?;
+                }
+                (ChangeField::Template, Some(before), Some(after)) => {
+                    tx.execute(
+                        "UPDATE OR IGNORE templates SET name = ?1 WHERE name = ?2 AND guild_id = ?3",
+                        params![before, after, guild_id],
+                    )?;
+                }
+                (ChangeField::Substitute, None, Some(created)) => {
+                    if let Some(id) = entry.substitute_id {
+                        Self::execute_forget_sub_draws(&tx, id)?;
+                    }
+                    tx.execute(
+                        "DELETE FROM substitutes WHERE id = ?1 AND name = ?2",
+                        params![entry.substitute_id, created],
+                    )?;
+                }
+                (ChangeField::Substitute, Some(removed), None) => {
+                    Self::execute_insert_template(
+                        &tx,
+                        guild_id,
+                        change.user_id,
+                        &change.template,
+                        &mut Vec::new(),
+                    )?;
+                    let template_id =
+                        Self::find_template_id_with_transaction(&tx, guild_id, &change.template)?;
+                    let (added_by, weight) = match &entry.removed {
+                        Some(state) => (state.owner_id, state.weight),
+                        None => (Some(change.user_id), 1),
+                    };
+
+                    // Keep the old id so later changes to the substitute can still be undone,
+                    // unless another substitute has taken it since.
+                    let result = tx.execute(
+                        "INSERT OR IGNORE INTO substitutes (id, name, template_id, weight, added_by)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![entry.substitute_id, removed, template_id, weight, added_by],
+                    )?;
+                    if result == 0 {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO substitutes (name, template_id, weight, added_by)
+                             VALUES (?1, ?2, ?3, ?4)",
+                            params![removed, template_id, weight, added_by],
+                        )?;
+                    }
+                }
+                (ChangeField::Substitute, Some(before), Some(after)) => {
+                    let result = tx.execute(
+                        "UPDATE OR IGNORE substitutes SET name = ?1 WHERE id = ?2 AND name = ?3",
+                        params![before, entry.substitute_id, after],
+                    )?;
+                    if let (true, Some(id)) = (result > 0, entry.substitute_id) {
+                        Self::execute_forget_sub_draws(&tx, id)?;
+                    }
+                }
+                (ChangeField::Locked, Some(before), Some(after)) => {
+                    tx.execute(
+                        "UPDATE templates SET locked = ?1
+                         WHERE name = ?2 AND guild_id = ?3 AND locked = ?4",
+                        params![before == "true", change.template, guild_id, after == "true"],
+                    )?;
+                }
+                (ChangeField::Deck, Some(before), Some(after)) => {
+                    let result = tx.execute(
+                        "UPDATE templates SET deck = ?1
+                         WHERE name = ?2 AND guild_id = ?3 AND deck = ?4",
+                        params![before == "true", change.template, guild_id, after == "true"],
+                    )?;
+                    if result > 0 && before != "true" {
+                        Self::execute_forget_draws(&tx, guild_id, &change.template)?;
+                    }
+                }
+                (ChangeField::Weight, Some(before), Some(after)) => {
+                    tx.execute(
+                        "UPDATE substitutes SET weight = ?1 WHERE id = ?2 AND weight = ?3",
+                        params![before, entry.substitute_id, after],
+                    )?;
+                }
+                _ => {}
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    /// Exports the given templates, or every template readable by the guild.
+    pub fn export_templates(
+        &self,
+        guild_id: u64,
+        templates: Option<&[&str]>,
+    ) -> rusqlite::Result<TemplateExport> {
+        let names = match templates {
+            Some(templates) => templates.iter().map(|t| t.to_string()).collect(),
+            None => self.get_templates(guild_id)?,
+        };
+
+        let mut exported = Vec::new();
+
+        for name in names {
+            let substitutes = self
+                .get_sub_records(guild_id, &name)?
+                .into_iter()
+                .map(|record| ExportedSubstitute {
+                    name: record.name,
+                    weight: record.weight,
+                })
+                .collect();
+
+            exported.push(ExportedTemplate { name, substitutes });
+        }
+
+        Ok(TemplateExport::new(exported))
+    }
+
+    fn execute_import(
+        tx: &Transaction,
+        guild_id: u64,
+        user_id: u64,
+        export: &TemplateExport,
+    ) -> rusqlite::Result<ImportSummary> {
+        let mut summary = ImportSummary::default();
+        // Every template shares one change so a single undo reverts the whole import.
+        let mut entries = Vec::new();
+
+        for template in &export.templates {
+            let created = entries.len();
+
+            Self::execute_insert_template(tx, guild_id, user_id, &template.name, &mut entries)?;
+            if entries.len() > created {
+                summary.templates += 1;
+            }
+
+            let template_id =
+                Self::find_template_id_with_transaction(tx, guild_id, &template.name)?;
+
+            for sub in &template.substitutes {
+                let result = tx.execute(
+                    "INSERT OR IGNORE INTO substitutes (name, template_id, weight, added_by)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![sub.name, template_id, sub.weight, user_id],
+                )?;
+
+                if result > 0 {
+                    summary.substitutes += 1;
+                    entries.push(ChangeEntry::substitute(
+                        tx.last_insert_rowid(),
+                        None,
+                        Some(sub.name.clone()),
+                    ));
+                }
+            }
+        }
+
+        let names = export
+            .templates
+            .iter()
+            .map(|template| template.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+
+        Self::execute_record_change(tx, guild_id, user_id, "import_templates", &names, &entries)?;
+
+        Ok(summary)
+    }
+
+    /// Counts the templates and substitutes an import would create without changing anything.
+    pub fn preview_import(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        export: &TemplateExport,
+    ) -> rusqlite::Result<ImportSummary> {
+        let tx = self.db.transaction()?;
+        Self::execute_import(&tx, guild_id, user_id, export)
+    }
+
+    /// Adds every template and substitute in an export. Existing substitutes are left untouched.
+    pub fn import_templates(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        export: &TemplateExport,
+    ) -> rusqlite::Result<ImportSummary> {
+        self.invalidate_cache();
+        let tx = self.db.transaction()?;
+        let summary = Self::execute_import(&tx, guild_id, user_id, export)?;
+        tx.commit()?;
+        Ok(summary)
+    }
+
+    /// Gets the owner and lock state of a template in the guild's own namespace.
+    pub fn get_template_info(
+        &self,
+        guild_id: u64,
+        template: &str,
+    ) -> rusqlite::Result<TemplateInfo> {
+        self.db.query_row(
+            "SELECT owner_id, locked FROM templates WHERE name = ?1 AND guild_id = ?2",
+            params![template, guild_id],
+            |row| {
+                Ok(TemplateInfo {
+                    owner_id: row.get(0)?,
+                    locked: row.get(1)?,
+                })
+            },
+        )
+    }
+
+    pub fn set_template_locked(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        template: &str,
+        locked: bool,
     ) -> rusqlite::Result<bool> {
         let tx = self.db.transaction()?;
+        let mut entries = Vec::new();
 
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+        let old_locked: Option<bool> = tx
+            .query_row(
+                "SELECT locked FROM templates WHERE name = ?1 AND guild_id = ?2",
+                params![template, guild_id],
+                |row| row.get(0),
+            )
+            .optional()?;
 
         let result = tx.execute(
-            "UPDATE substitutes SET name = ?1 WHERE id = ?2 AND template_id = ?3",
-            &[new_sub, &id.to_string(), &template_id],
+            "UPDATE templates SET locked = ?1 WHERE name = ?2 AND guild_id = ?3",
+            params![locked, template, guild_id],
         )?;
 
+        if let Some(old_locked) = old_locked.filter(|old_locked| *old_locked != locked) {
+            entries.push(ChangeEntry::setting(
+                ChangeField::Locked,
+                old_locked,
+                locked,
+            ));
+        }
+
+        Self::execute_record_change(&tx, guild_id, user_id, "lock_template", template, &entries)?;
+
         tx.commit()?;
 
         Ok(result > 0)
     }
 
+    /// Enables or disables deck mode for a template. Disabling deck mode forgets every draw.
+    pub fn set_deck_mode(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        template: &str,
+        deck: bool,
+    ) -> rusqlite::Result<bool> {
+        self.invalidate_cache();
+        let tx = self.db.transaction()?;
+        let mut entries = Vec::new();
+
+        let old_deck: Option<bool> = tx
+            .query_row(
+                "SELECT deck FROM templates WHERE name = ?1 AND guild_id = ?2",
+                params![template, guild_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let result = tx.execute(
+            "UPDATE templates SET deck = ?1 WHERE name = ?2 AND guild_id = ?3",
+            params![deck, template, guild_id],
+        )?;
+
+        if !deck {
+            Self::execute_forget_draws(&tx, guild_id, template)?;
+        }
+
+        if let Some(old_deck) = old_deck.filter(|old_deck| *old_deck != deck) {
+            entries.push(ChangeEntry::setting(ChangeField::Deck, old_deck, deck));
+        }
+
+        Self::execute_record_change(&tx, guild_id, user_id, "deck_mode", template, &entries)?;
+
+        tx.commit()?;
+
+        Ok(result > 0)
+    }
+
+    fn execute_forget_draws(
+        tx: &Transaction,
+        guild_id: u64,
+        template: &str,
+    ) -> rusqlite::Result<()> {
+        tx.execute(
+            "DELETE FROM deck_draws WHERE template_id IN (
+                SELECT id FROM templates WHERE name = ?1 AND guild_id = ?2
+             )",
+            params![template, guild_id],
+        )?;
+        Ok(())
+    }
+
     pub fn clear(&self) -> rusqlite::Result<()> {
+        self.invalidate_cache();
+        self.db.execute("DELETE FROM deck_draws", [])?;
         self.db.execute("DELETE FROM substitutes", [])?;
         self.db.execute("DELETE FROM templates", [])?;
+        self.db.execute("DELETE FROM change_entries", [])?;
+        self.db.execute("DELETE FROM changes", [])?;
+        self.db.execute("DELETE FROM stored_variables", [])?;
         Ok(())
     }
 
-    fn find_template_id(&self, template: &str) -> rusqlite::Result<String> {
+    /// Allows or prevents a guild from reading templates stored in the legacy namespace.
+    pub fn set_legacy_access(&self, guild_id: u64, enabled: bool) -> rusqlite::Result<()> {
+        self.invalidate_cache();
+        self.db.execute(
+            "INSERT INTO guild_settings (guild_id, read_legacy_templates) VALUES (?1, ?2)
+             ON CONFLICT(guild_id) DO UPDATE SET read_legacy_templates = ?2",
+            params![guild_id, enabled],
+        )?;
+        Ok(())
+    }
+
+    pub fn has_legacy_access(&self, guild_id: u64) -> rusqlite::Result<bool> {
         let mut stmt = self
             .db
-            .prepare("SELECT id FROM templates WHERE name = ?1")?;
-        let template_id: i64 = stmt.query_row(&[template], |row| row.get(0))?;
+            .prepare("SELECT read_legacy_templates FROM guild_settings WHERE guild_id = ?1")?;
+        let mut rows = stmt.query([guild_id])?;
+
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(false),
+        }
+    }
+
+    /// Stores a value saved by FSL code, replacing any value already stored under the name. Values
+    /// shared by the whole guild are stored with a user id of [`GUILD_VARIABLE_USER_ID`].
+    ///
+    /// Returns false without storing the value if the size of every value stored in the guild
+    /// would exceed the byte limit.
+    pub fn store_variable(
+        &mut self,
+        guild_id: u64,
+        user_id: u64,
+        name: &str,
+        value: &str,
+        size: usize,
+        byte_limit: usize,
+    ) -> rusqlite::Result<bool> {
+        let tx = self.db.transaction()?;
+
+        let stored_size: usize = tx.query_row(
+            "SELECT COALESCE(SUM(size), 0) FROM stored_variables
+             WHERE guild_id = ?1 AND NOT (user_id = ?2 AND name = ?3)",
+            params![guild_id, user_id, name],
+            |row| row.get(0),
+        )?;
+
+        if stored_size.saturating_add(size) > byte_limit {
+            return Ok(false);
+        }
+
+        tx.execute(
+            "INSERT INTO stored_variables (guild_id, user_id, name, value, size)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(guild_id, user_id, name) DO UPDATE SET value = ?4, size = ?5",
+            params![guild_id, user_id, name, value, size],
+        )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    /// Loads a value saved by FSL code, returning None if nothing is stored under the name.
+    pub fn load_variable(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        name: &str,
+    ) -> rusqlite::Result<Option<String>> {
+        self.db
+            .query_row(
+                "SELECT value FROM stored_variables
+                 WHERE guild_id = ?1 AND user_id = ?2 AND name = ?3",
+                params![guild_id, user_id, name],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Finds a template readable by the guild, preferring the guild's own template over a legacy
+    /// template of the same name.
+    fn find_template_id(&self, guild_id: u64, template: &str) -> rusqlite::Result<String> {
+        let mut stmt = self.db.prepare(
+            "SELECT id FROM templates
+             WHERE name = ?1
+             AND (guild_id = ?2 OR (guild_id = ?3 AND EXISTS (
+                SELECT 1 FROM guild_settings
+                WHERE guild_id = ?2 AND read_legacy_templates = 1
+             )))
+             ORDER BY guild_id = ?3 ASC
+             LIMIT 1",
+        )?;
+        let template_id: i64 = stmt
+            .query_row(params![template, guild_id, LEGACY_GUILD_ID], |row| {
+                row.get(0)
+            })?;
         Ok(template_id.to_string())
     }
 
-    pub fn get_sub_records(&self, template: &str) -> rusqlite::Result<Vec<SubstituteRecord>> {
-        let template_id = self.find_template_id(template)?;
-        let mut stmt = self
-            .db
-            .prepare("SELECT id, name, template_id FROM substitutes WHERE template_id = ?1")?;
+    pub fn get_sub_records(
+        &self,
+        guild_id: u64,
+        template: &str,
+    ) -> rusqlite::Result<Vec<SubstituteRecord>> {
+        let template_id = self.find_template_id(guild_id, template)?;
+        let mut stmt = self.db.prepare(
+            "SELECT id, name, template_id, weight FROM substitutes WHERE template_id = ?1",
+        )?;
 
         let records: Result<Vec<SubstituteRecord>, rusqlite::Error> = stmt
             .query_map([template_id], |row| {
@@ -428,6 +1926,7 @@ impl FunboyDatabase {
                     id: row.get(0)?,
                     name: row.get(1)?,
                     template_id: row.get(2)?,
+                    weight: row.get(3)?,
                 })
             })?
             .collect();
@@ -435,8 +1934,8 @@ impl FunboyDatabase {
         Ok(records?)
     }
 
-    pub fn get_subs(&self, template: &str) -> rusqlite::Result<Vec<String>> {
-        let template_id = self.find_template_id(template)?;
+    pub fn get_subs(&self, guild_id: u64, template: &str) -> rusqlite::Result<Vec<String>> {
+        let template_id = self.find_template_id(guild_id, template)?;
         let mut stmt = self.db.prepare(
             "SELECT substitutes.name
              FROM substitutes
@@ -452,48 +1951,177 @@ impl FunboyDatabase {
             .collect())
     }
 
-    pub fn get_random_subs(&self, template: &str) -> rusqlite::Result<String> {
-        let template_id = self.find_template_id(template)?;
+    /// Picks a random substitute from a template where each substitute's chance of being picked
+    /// is proportional to its weight.
+    ///
+    /// Templates in deck mode are drawn from without replacement, each channel drawing from its
+    /// own deck.
+    ///
+    /// The same random number generator state picks the same substitute as long as the template
+    /// and deck haven't changed.
+    ///
+    /// Returns None if the template has no substitutes with a weight above zero.
+    pub fn get_random_sub_record(
+        &mut self,
+        guild_id: u64,
+        channel_id: u64,
+        template: &str,
+        rng: &mut impl Rng,
+    ) -> rusqlite::Result<Option<SubstituteRecord>> {
+        self.choose_cached_sub(guild_id, channel_id, template, rng)
+    }
+
+    /// Picks a random substitute like [`FunboyDatabase::get_random_sub_record`].
+    ///
+    /// Returns an empty string if the template has no substitutes with a weight above zero.
+    pub fn get_random_subs(
+        &mut self,
+        guild_id: u64,
+        channel_id: u64,
+        template: &str,
+        rng: &mut impl Rng,
+    ) -> rusqlite::Result<String> {
+        Ok(self
+            .get_random_sub_record(guild_id, channel_id, template, rng)?
+            .map(|record| record.name)
+            .unwrap_or_default())
+    }
+
+    /// Searches substitutes readable by the guild for every word in the query.
+    pub fn search_subs(
+        &self,
+        guild_id: u64,
+        query: &str,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<SubstituteMatch>> {
+        // Quote every word so characters such as `^` aren't parsed as FTS5 query syntax.
+        let query = query
+            .split_whitespace()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+
         let mut stmt = self.db.prepare(
-            "SELECT substitutes.name
-             FROM substitutes
-             WHERE template_id = ?1
-             ORDER BY RANDOM() LIMIT 1;",
+            "SELECT templates.name, substitutes.id, substitutes.name
+             FROM substitutes_fts
+             JOIN substitutes ON substitutes.id = substitutes_fts.rowid
+             JOIN templates ON templates.id = substitutes.template_id
+             WHERE substitutes_fts MATCH ?1
+             AND (templates.guild_id = ?2 OR (templates.guild_id = ?3 AND EXISTS (
+                SELECT 1 FROM guild_settings
+                WHERE guild_id = ?2 AND read_legacy_templates = 1
+             )))
+             ORDER BY LOWER(templates.name) ASC, templates.guild_id DESC, substitutes.id ASC
+             LIMIT ?4",
         )?;
 
-        let mut rows = stmt.query([template_id])?;
+        let matches = stmt
+            .query_map(params![query, guild_id, LEGACY_GUILD_ID, limit], |row| {
+                Ok(SubstituteMatch {
+                    template: row.get(0)?,
+                    id: row.get(1)?,
+                    name: row.get(2)?,
+                })
+            })?
+            .collect();
 
-        match rows.next()? {
-            Some(row) => {
-                let sub: String = row.get(0)?;
-                return Ok(sub);
-            }
-            _ => Ok("".to_string()),
-        }
+        matches
     }
 
-    pub fn get_templates(&self) -> rusqlite::Result<Vec<String>> {
+    pub fn get_templates(&self, guild_id: u64) -> rusqlite::Result<Vec<String>> {
         let mut stmt = self.db.prepare(
-            "SELECT templates.name
+            "SELECT DISTINCT templates.name
              FROM templates
+             WHERE guild_id = ?1
+             OR (guild_id = ?2 AND EXISTS (
+                SELECT 1 FROM guild_settings
+                WHERE guild_id = ?1 AND read_legacy_templates = 1
+             ))
              ORDER BY LOWER(templates.name) ASC;",
         )?;
 
-        let templates = stmt.query_map([], |row| row.get(0))?;
+        let templates = stmt.query_map(params![guild_id, LEGACY_GUILD_ID], |row| row.get(0))?;
 
         Ok(templates
             .filter(|x| x.is_ok())
             .map(|x| x.unwrap())
             .collect())
     }
+
+    /// Checks the guild's templates for dangling references, empty templates, templates nothing
+    /// references and reference loops. Legacy templates are only used to resolve references.
+    pub fn lint_templates(&self, guild_id: u64) -> rusqlite::Result<LintReport> {
+        let mut stmt = self.db.prepare(
+            "SELECT templates.id, templates.name, substitutes.id, substitutes.name, substitutes.weight
+             FROM templates
+             LEFT JOIN substitutes ON substitutes.template_id = templates.id
+             WHERE templates.guild_id = ?1
+             ORDER BY LOWER(templates.name) ASC, substitutes.id ASC",
+        )?;
+        let rows = stmt.query_map([guild_id], |row| {
+            let template_id: i32 = row.get(0)?;
+            let template: String = row.get(1)?;
+            let sub = match row.get::<_, Option<i32>>(2)? {
+                Some(id) => Some(SubstituteRecord {
+                    id,
+                    name: row.get(3)?,
+                    template_id,
+                    weight: row.get(4)?,
+                }),
+                None => None,
+            };
+            Ok((template, sub))
+        })?;
+
+        let mut templates: Vec<LintTemplate> = Vec::new();
+
+        for row in rows {
+            let (name, sub) = row?;
+
+            if templates.last().map(|t| &t.name) != Some(&name) {
+                templates.push(LintTemplate {
+                    name,
+                    substitutes: Vec::new(),
+                });
+            }
+
+            if let (Some(template), Some(sub)) = (templates.last_mut(), sub) {
+                template.substitutes.push(sub);
+            }
+        }
+
+        let mut other_templates = HashSet::new();
+
+        if guild_id != LEGACY_GUILD_ID && self.has_legacy_access(guild_id)? {
+            let mut stmt = self
+                .db
+                .prepare("SELECT name FROM templates WHERE guild_id = ?1")?;
+            for name in stmt.query_map([LEGACY_GUILD_ID], |row| row.get::<_, String>(0))? {
+                other_templates.insert(name?.to_ascii_lowercase());
+            }
+        }
+
+        Ok(lint_templates(&templates, &other_templates))
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::vec;
 
+    use rand::{rngs::StdRng, SeedableRng};
+
     use super::*;
 
+    const TEST_GUILD_ID: u64 = 1;
+    const TEST_USER_ID: u64 = 1;
+    const TEST_CHANNEL_ID: u64 = 1;
+
     const NOUNS: &[&str] = &[
         "cat",
         "dog",
@@ -534,21 +2162,24 @@ mod tests {
     fn get_inside_empty_database() {
         let db = FunboyDatabase::from_path("test1.db").unwrap();
 
-        db.get_subs("noun").unwrap();
+        db.get_subs(TEST_GUILD_ID, "noun").unwrap();
     }
 
     #[test]
     fn insert_new_templates_with_subtitutions() {
         let mut db = FunboyDatabase::from_path("test2.db").unwrap();
 
-        db.insert_subs("noun", Some(NOUNS)).unwrap();
-        db.insert_subs("verb", Some(VERBS)).unwrap();
-        db.insert_subs("adj", Some(ADJECTIVES)).unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "verb", Some(VERBS))
+            .unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "adj", Some(ADJECTIVES))
+            .unwrap();
 
-        let templates = db.get_templates().unwrap();
-        let noun_subs = db.get_subs("noun").unwrap();
-        let verb_subs = db.get_subs("verb").unwrap();
-        let adj_subs = db.get_subs("adj").unwrap();
+        let templates = db.get_templates(TEST_GUILD_ID).unwrap();
+        let noun_subs = db.get_subs(TEST_GUILD_ID, "noun").unwrap();
+        let verb_subs = db.get_subs(TEST_GUILD_ID, "verb").unwrap();
+        let adj_subs = db.get_subs(TEST_GUILD_ID, "adj").unwrap();
 
         assert!(templates.contains(&"noun".to_string()));
         assert!(templates.contains(&"adj".to_string()));
@@ -568,55 +2199,86 @@ mod tests {
     fn insert_only_template() {
         let mut db = FunboyDatabase::from_path("test4.db").unwrap();
 
-        db.insert_subs("template-with-no-subs", Some(&[])).unwrap();
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "template-with-no-subs",
+            Some(&[]),
+        )
+        .unwrap();
 
         let empty: Vec<String> = Vec::new();
-        assert_eq!(db.get_subs("template-with-no-subs").unwrap(), empty);
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "template-with-no-subs").unwrap(),
+            empty
+        );
     }
 
     #[test]
     fn remove_substitutes() {
         let mut db = FunboyDatabase::from_path("test5.db").unwrap();
 
-        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
 
-        assert_eq!(db.get_subs("noun").unwrap().len(), NOUNS.len());
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "noun").unwrap().len(),
+            NOUNS.len()
+        );
 
         let empty: Vec<String> = Vec::new();
 
-        db.remove_subs("noun", NOUNS).unwrap();
+        db.remove_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", NOUNS)
+            .unwrap();
 
-        assert_eq!(db.get_subs("noun").unwrap(), empty);
+        assert_eq!(db.get_subs(TEST_GUILD_ID, "noun").unwrap(), empty);
 
-        db.insert_subs("verb", Some(VERBS)).unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "verb", Some(VERBS))
+            .unwrap();
 
-        assert_eq!(db.get_subs("verb").unwrap().len(), VERBS.len());
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "verb").unwrap().len(),
+            VERBS.len()
+        );
 
-        db.remove_subs("verb", &["JAFLJE;LSFKALESF"]).unwrap();
+        db.remove_subs(TEST_GUILD_ID, TEST_USER_ID, "verb", &["JAFLJE;LSFKALESF"])
+            .unwrap();
 
-        db.remove_subs("verb", &["jump"]).unwrap();
+        db.remove_subs(TEST_GUILD_ID, TEST_USER_ID, "verb", &["jump"])
+            .unwrap();
 
-        assert!(!db.get_subs("verb").unwrap().contains(&"jump".to_string()));
+        assert!(!db
+            .get_subs(TEST_GUILD_ID, "verb")
+            .unwrap()
+            .contains(&"jump".to_string()));
     }
 
     #[test]
     fn remove_template() {
         let mut db = FunboyDatabase::from_path("test6.db").unwrap();
 
-        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
 
-        assert_eq!(db.get_subs("noun").unwrap().len(), NOUNS.len());
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "noun").unwrap().len(),
+            NOUNS.len()
+        );
 
-        db.remove_template("noun").unwrap();
+        db.remove_template(TEST_GUILD_ID, TEST_USER_ID, "noun")
+            .unwrap();
 
-        assert!(!db.get_templates().unwrap().contains(&"noun".to_string()));
+        assert!(!db
+            .get_templates(TEST_GUILD_ID)
+            .unwrap()
+            .contains(&"noun".to_string()));
     }
 
     #[test]
     fn remove_non_existant_template() {
         let mut db = FunboyDatabase::from_path("test6.db").unwrap();
 
-        match db.remove_template("noun") {
+        match db.remove_template(TEST_GUILD_ID, TEST_USER_ID, "noun") {
             Ok(_) => {}
             Err(rusqlite::Error::QueryReturnedNoRows) => {
                 dbg!("Ignoring query returned no rows error...");
@@ -626,7 +2288,10 @@ mod tests {
             }
         }
 
-        assert!(!db.get_templates().unwrap().contains(&"noun".to_string()));
+        assert!(!db
+            .get_templates(TEST_GUILD_ID)
+            .unwrap()
+            .contains(&"noun".to_string()));
     }
 
     #[test]
@@ -635,24 +2300,116 @@ mod tests {
 
         db.clear().unwrap();
 
-        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
 
-        db.rename_template("noun", "new-nouns").unwrap();
+        db.rename_template(TEST_GUILD_ID, TEST_USER_ID, "noun", "new-nouns")
+            .unwrap();
 
-        assert_eq!(db.get_templates().unwrap(), vec!["new-nouns"]);
+        assert_eq!(db.get_templates(TEST_GUILD_ID).unwrap(), vec!["new-nouns"]);
     }
 
     #[test]
-    fn insert_substitutes_with_same_name() {
-        let mut db = FunboyDatabase::from_path("test8.db").unwrap();
+    fn rename_template_references() {
+        let mut db = FunboyDatabase::from_path("test19.db").unwrap();
 
         db.clear().unwrap();
 
-        db.insert_subs("noun", Some(&["example", "example2"]))
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "sentence",
+            Some(&["^noun and ^nouns2", "{get_sub( \"noun\" )}", "^nounphrase"]),
+        )
+        .unwrap();
+
+        let preview = db
+            .preview_rename_template(TEST_GUILD_ID, "noun", "thing")
+            .unwrap();
+        assert_eq!(preview.len(), 2);
+        assert_eq!(
+            db.get_templates(TEST_GUILD_ID).unwrap(),
+            vec!["noun", "sentence"]
+        );
+
+        db.rename_template(TEST_GUILD_ID, TEST_USER_ID, "noun", "thing")
+            .unwrap();
+
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "sentence").unwrap(),
+            vec![
+                "^nounphrase",
+                "^thing and ^nouns2",
+                "{get_sub( \"thing\" )}"
+            ]
+        );
+
+        let change = db
+            .get_last_change(TEST_GUILD_ID, TEST_USER_ID)
+            .unwrap()
             .unwrap();
+        assert_eq!(
+            db.get_change_templates(TEST_GUILD_ID, &change).unwrap(),
+            vec!["thing", "sentence"]
+        );
+    }
+
+    #[test]
+    fn rename_missing_template() {
+        let mut db = FunboyDatabase::from_path("test32.db").unwrap();
+
+        db.clear().unwrap();
 
-        db.insert_subs("noun2", Some(&["example", "example2"]))
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "sentence",
+            Some(&["^missing and {get_sub(\"missing\")}"]),
+        )
+        .unwrap();
+
+        assert!(!db
+            .rename_template(TEST_GUILD_ID, TEST_USER_ID, "missing", "thing")
+            .unwrap());
+
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "sentence").unwrap(),
+            vec!["^missing and {get_sub(\"missing\")}"]
+        );
+
+        let change = db
+            .get_last_change(TEST_GUILD_ID, TEST_USER_ID)
+            .unwrap()
             .unwrap();
+        assert_eq!(
+            db.get_change_templates(TEST_GUILD_ID, &change).unwrap(),
+            vec!["sentence"]
+        );
+    }
+
+    #[test]
+    fn insert_substitutes_with_same_name() {
+        let mut db = FunboyDatabase::from_path("test8.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "noun",
+            Some(&["example", "example2"]),
+        )
+        .unwrap();
+
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "noun2",
+            Some(&["example", "example2"]),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -661,12 +2418,831 @@ mod tests {
 
         db.clear().unwrap();
 
-        db.insert_subs("noun", Some(&["example", "example2"]))
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "noun",
+            Some(&["example", "example2"]),
+        )
+        .unwrap();
+
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "noun",
+            Some(&["example", "example2"]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "noun").unwrap(),
+            &["example", "example2"]
+        );
+    }
+
+    #[test]
+    fn templates_are_scoped_by_guild() {
+        let mut db = FunboyDatabase::from_path("test10.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+        db.insert_subs(2, TEST_USER_ID, "noun", Some(&["example"]))
+            .unwrap();
+
+        db.remove_template(2, TEST_USER_ID, "noun").unwrap();
+
+        assert!(db.get_templates(2).unwrap().is_empty());
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "noun").unwrap().len(),
+            NOUNS.len()
+        );
+    }
+
+    #[test]
+    fn read_legacy_templates() {
+        let mut db = FunboyDatabase::from_path("test11.db").unwrap();
+
+        db.clear().unwrap();
+        db.set_legacy_access(TEST_GUILD_ID, false).unwrap();
+
+        db.insert_subs(LEGACY_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+
+        assert!(db.get_subs(TEST_GUILD_ID, "noun").is_err());
+
+        db.set_legacy_access(TEST_GUILD_ID, true).unwrap();
+
+        assert!(db.has_legacy_access(TEST_GUILD_ID).unwrap());
+        assert_eq!(db.get_templates(TEST_GUILD_ID).unwrap(), vec!["noun"]);
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "noun").unwrap().len(),
+            NOUNS.len()
+        );
+
+        // Legacy templates are read only from a guild's point of view
+        assert!(db
+            .remove_template(TEST_GUILD_ID, TEST_USER_ID, "noun")
+            .is_err());
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(&["example"]))
+            .unwrap();
+
+        assert_eq!(db.get_subs(TEST_GUILD_ID, "noun").unwrap(), vec!["example"]);
+    }
+
+    #[test]
+    fn upgrade_unscoped_templates_to_legacy_namespace() {
+        let path = "test12.db";
+        let _ = std::fs::remove_file(path);
+
+        {
+            let db = Connection::open(path).unwrap();
+            db.execute_batch(
+                "CREATE TABLE templates (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL UNIQUE COLLATE NOCASE
+                );
+                CREATE TABLE substitutes (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL COLLATE NOCASE,
+                    template_id INTEGER NOT NULL REFERENCES templates(id),
+                    UNIQUE(name, template_id)
+                );
+                INSERT INTO templates (id, name) VALUES (1, 'noun');
+                INSERT INTO substitutes (name, template_id) VALUES ('cat', 1);
+                PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        }
+
+        let db = FunboyDatabase::from_path(path).unwrap();
+
+        assert_eq!(db.get_subs(LEGACY_GUILD_ID, "noun").unwrap(), vec!["cat"]);
+        assert!(db.get_subs(TEST_GUILD_ID, "noun").is_err());
+    }
+
+    #[test]
+    fn upgrade_unversioned_database_to_latest() {
+        let path = "test30.db";
+        let _ = std::fs::remove_file(path);
+
+        {
+            let db = Connection::open(path).unwrap();
+            db.execute_batch(
+                "CREATE TABLE templates (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL UNIQUE
+                );
+                CREATE TABLE substitutes (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    template_id INTEGER NOT NULL REFERENCES templates(id)
+                );
+                INSERT INTO templates (id, name) VALUES (1, 'noun');
+                INSERT INTO substitutes (name, template_id) VALUES ('cat', 1);",
+            )
+            .unwrap();
+        }
+
+        let mut db = FunboyDatabase::from_path(path).unwrap();
+
+        assert_eq!(
+            FunboyDatabase::get_schema_version(&db.db).unwrap(),
+            DATABASE_VERSION
+        );
+        assert_eq!(db.get_subs(LEGACY_GUILD_ID, "noun").unwrap(), vec!["cat"]);
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(&["dog"]))
+            .unwrap();
+        assert_eq!(db.get_subs(TEST_GUILD_ID, "noun").unwrap(), vec!["dog"]);
+    }
+
+    #[test]
+    fn weighted_random_substitutes() {
+        let mut db = FunboyDatabase::from_path("test13.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+
+        let records = db.get_sub_records(TEST_GUILD_ID, "noun").unwrap();
+        assert!(records.iter().all(|record| record.weight == 1));
+
+        for record in &records[1..] {
+            db.set_sub_weight(TEST_GUILD_ID, TEST_USER_ID, "noun", record.id as usize, 0)
+                .unwrap();
+        }
+        db.set_sub_weight(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "noun",
+            records[0].id as usize,
+            5,
+        )
+        .unwrap();
+
+        for _ in 0..20 {
+            assert_eq!(
+                db.get_random_subs(
+                    TEST_GUILD_ID,
+                    TEST_CHANNEL_ID,
+                    "noun",
+                    &mut rand::thread_rng()
+                )
+                .unwrap(),
+                records[0].name
+            );
+        }
+
+        db.set_sub_weight(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "noun",
+            records[0].id as usize,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "noun",
+                &mut rand::thread_rng()
+            )
+            .unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn maximum_substitute_weights() {
+        let mut db = FunboyDatabase::from_path("test25.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(&["cat", "dog"]))
+            .unwrap();
+
+        let records = db.get_sub_records(TEST_GUILD_ID, "noun").unwrap();
+        for record in &records {
+            db.set_sub_weight(
+                TEST_GUILD_ID,
+                TEST_USER_ID,
+                "noun",
+                record.id as usize,
+                u32::MAX,
+            )
+            .unwrap();
+        }
+
+        let picked: HashSet<String> = (0..50)
+            .map(|_| {
+                db.get_random_subs(
+                    TEST_GUILD_ID,
+                    TEST_CHANNEL_ID,
+                    "noun",
+                    &mut rand::thread_rng(),
+                )
+                .unwrap()
+            })
+            .collect();
+
+        assert_eq!(
+            picked,
+            HashSet::from(["cat".to_string(), "dog".to_string()])
+        );
+    }
+
+    #[test]
+    fn template_ownership_and_locking() {
+        let mut db = FunboyDatabase::from_path("test14.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+        db.insert_subs(TEST_GUILD_ID, 2, "noun", Some(&["example"]))
+            .unwrap();
+
+        let info = db.get_template_info(TEST_GUILD_ID, "noun").unwrap();
+        assert_eq!(info.owner_id, Some(TEST_USER_ID));
+        assert!(!info.locked);
+
+        assert!(db
+            .set_template_locked(TEST_GUILD_ID, TEST_USER_ID, "noun", true)
+            .unwrap());
+        assert!(db.get_template_info(TEST_GUILD_ID, "noun").unwrap().locked);
+
+        let change = db
+            .get_last_change(TEST_GUILD_ID, TEST_USER_ID)
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.action, "lock_template");
+        assert!(db.undo_change(TEST_GUILD_ID, &change).unwrap());
+        assert!(!db.get_template_info(TEST_GUILD_ID, "noun").unwrap().locked);
+
+        assert!(!db
+            .set_template_locked(TEST_GUILD_ID, TEST_USER_ID, "verb", true)
+            .unwrap());
+        assert!(matches!(
+            db.get_template_info(TEST_GUILD_ID, "verb"),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
+    }
+
+    #[test]
+    fn undo_changes() {
+        let mut db = FunboyDatabase::from_path("test15.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "sentence", Some(&["a ^noun"]))
+            .unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "escaped", Some(&["\\^noun"]))
+            .unwrap();
+        db.remove_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", &["cat", "dog"])
+            .unwrap();
+
+        let change = db
+            .get_last_change(TEST_GUILD_ID, TEST_USER_ID)
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.action, "remove_subs");
+        assert_eq!(change.entries.len(), 2);
+
+        assert!(db.undo_change(TEST_GUILD_ID, &change).unwrap());
+        assert!(!db.undo_change(TEST_GUILD_ID, &change).unwrap());
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "noun").unwrap().len(),
+            NOUNS.len()
+        );
+
+        db.rename_template(TEST_GUILD_ID, TEST_USER_ID, "noun", "thing")
+            .unwrap();
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "sentence").unwrap(),
+            vec!["a ^thing"]
+        );
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "escaped").unwrap(),
+            vec!["\\^noun"]
+        );
+
+        let change = db
+            .get_last_change(TEST_GUILD_ID, TEST_USER_ID)
+            .unwrap()
+            .unwrap();
+        assert!(db.undo_change(TEST_GUILD_ID, &change).unwrap());
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "noun").unwrap().len(),
+            NOUNS.len()
+        );
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "sentence").unwrap(),
+            vec!["a ^noun"]
+        );
+
+        let history = db.get_history(TEST_GUILD_ID, "noun", 10).unwrap();
+        let actions: Vec<&str> = history.iter().map(|c| c.action.as_str()).collect();
+        assert_eq!(
+            actions,
+            vec!["rename_template", "remove_subs", "insert_subs"]
+        );
+        assert!(history.iter().take(2).all(|c| c.undone));
+
+        db.remove_template(TEST_GUILD_ID, TEST_USER_ID, "noun")
+            .unwrap();
+        let change = db
+            .get_last_change(TEST_GUILD_ID, TEST_USER_ID)
+            .unwrap()
+            .unwrap();
+        assert!(db.undo_change(TEST_GUILD_ID, &change).unwrap());
+        assert_eq!(
+            db.get_subs(TEST_GUILD_ID, "noun").unwrap().len(),
+            NOUNS.len()
+        );
+    }
+
+    #[test]
+    fn undo_restores_removed_state() {
+        let mut db = FunboyDatabase::from_path("test26.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, 2, "noun", Some(NOUNS))
+            .unwrap();
+        let records = db.get_sub_records(TEST_GUILD_ID, "noun").unwrap();
+        db.set_sub_weight(TEST_GUILD_ID, 2, "noun", records[0].id as usize, 3)
+            .unwrap();
+        db.set_template_locked(TEST_GUILD_ID, TEST_USER_ID, "noun", true)
+            .unwrap();
+        db.set_deck_mode(TEST_GUILD_ID, TEST_USER_ID, "noun", true)
+            .unwrap();
+
+        db.remove_template(TEST_GUILD_ID, TEST_USER_ID, "noun")
+            .unwrap();
+        let change = db
+            .get_last_change(TEST_GUILD_ID, TEST_USER_ID)
+            .unwrap()
+            .unwrap();
+        assert!(db.undo_change(TEST_GUILD_ID, &change).unwrap());
+
+        let info = db.get_template_info(TEST_GUILD_ID, "noun").unwrap();
+        assert_eq!(info.owner_id, Some(2));
+        assert!(info.locked);
+        let deck: bool = db
+            .db
+            .query_row(
+                "SELECT deck FROM templates WHERE name = 'noun'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(deck);
+
+        let summary = |records: Vec<SubstituteRecord>| {
+            let mut summary: Vec<(i32, String, u32)> = records
+                .into_iter()
+                .map(|record| (record.id, record.name, record.weight))
+                .collect();
+            summary.sort();
+            summary
+        };
+        let mut expected = records;
+        expected[0].weight = 3;
+        assert_eq!(
+            summary(db.get_sub_records(TEST_GUILD_ID, "noun").unwrap()),
+            summary(expected)
+        );
+
+        db.insert_sub(TEST_GUILD_ID, TEST_USER_ID, "noun", "bird")
+            .unwrap();
+        let change = db
+            .get_last_change(TEST_GUILD_ID, TEST_USER_ID)
+            .unwrap()
+            .unwrap();
+        db.replace_substitute(TEST_GUILD_ID, 2, "noun", "bird", "fish")
+            .unwrap();
+        assert!(db.undo_change(TEST_GUILD_ID, &change).unwrap());
+        assert!(db
+            .get_subs(TEST_GUILD_ID, "noun")
+            .unwrap()
+            .contains(&"fish".to_string()));
+    }
+
+    #[test]
+    fn export_and_import_templates() {
+        let mut db = FunboyDatabase::from_path("test16.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "verb", Some(VERBS))
+            .unwrap();
+        let id = db.get_sub_records(TEST_GUILD_ID, "noun").unwrap()[0].id;
+        db.set_sub_weight(TEST_GUILD_ID, TEST_USER_ID, "noun", id as usize, 3)
             .unwrap();
 
-        db.insert_subs("noun", Some(&["example", "example2"]))
+        let export = db.export_templates(TEST_GUILD_ID, Some(&["noun"])).unwrap();
+        assert_eq!(export.templates.len(), 1);
+        assert_eq!(export.templates[0].substitutes.len(), NOUNS.len());
+
+        let export = TemplateExport::from_json(export.to_json().unwrap().as_bytes()).unwrap();
+
+        let expected = ImportSummary {
+            templates: 1,
+            substitutes: NOUNS.len(),
+        };
+        assert_eq!(
+            db.preview_import(2, TEST_USER_ID, &export).unwrap(),
+            expected
+        );
+        assert!(db.get_templates(2).unwrap().is_empty());
+
+        assert_eq!(
+            db.import_templates(2, TEST_USER_ID, &export).unwrap(),
+            expected
+        );
+        assert_eq!(
+            db.import_templates(2, TEST_USER_ID, &export).unwrap(),
+            ImportSummary::default()
+        );
+
+        let records = db.get_sub_records(2, "noun").unwrap();
+        assert_eq!(records.len(), NOUNS.len());
+        assert_eq!(records.iter().filter(|r| r.weight == 3).count(), 1);
+
+        let all = db.export_templates(TEST_GUILD_ID, None).unwrap();
+        assert_eq!(all.templates.len(), 2);
+
+        db.import_templates(3, TEST_USER_ID, &all).unwrap();
+        assert_eq!(db.get_templates(3).unwrap(), vec!["noun", "verb"]);
+        assert_eq!(db.get_history(3, "verb", 10).unwrap().len(), 1);
+
+        let change = db.get_last_change(3, TEST_USER_ID).unwrap().unwrap();
+        let templates = db.get_change_templates(3, &change).unwrap();
+        assert!(templates.contains(&"noun".to_string()));
+        assert!(templates.contains(&"verb".to_string()));
+        assert!(db.undo_change(3, &change).unwrap());
+        assert!(db.get_templates(3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn search_substitutes() {
+        let mut db = FunboyDatabase::from_path("test17.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "quote",
+            Some(&["Quoth the raven", "the ^noun jumped"]),
+        )
+        .unwrap();
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "bird",
+            Some(&["raven", "crow"]),
+        )
+        .unwrap();
+        db.insert_subs(2, TEST_USER_ID, "bird", Some(&["raven"]))
             .unwrap();
 
-        assert_eq!(db.get_subs("noun").unwrap(), &["example", "example2"]);
+        let matches = db.search_subs(TEST_GUILD_ID, "RAVEN", 10).unwrap();
+        let found: Vec<(&str, &str)> = matches
+            .iter()
+            .map(|m| (m.template.as_str(), m.name.as_str()))
+            .collect();
+        assert_eq!(found, vec![("bird", "raven"), ("quote", "Quoth the raven")]);
+
+        assert_eq!(db.search_subs(TEST_GUILD_ID, "^noun", 10).unwrap().len(), 1);
+        assert!(db.search_subs(TEST_GUILD_ID, "\"", 10).unwrap().is_empty());
+
+        db.replace_substitute(TEST_GUILD_ID, TEST_USER_ID, "bird", "raven", "magpie")
+            .unwrap();
+        db.remove_template(TEST_GUILD_ID, TEST_USER_ID, "quote")
+            .unwrap();
+        assert!(db
+            .search_subs(TEST_GUILD_ID, "raven", 10)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            db.search_subs(TEST_GUILD_ID, "magpie", 10).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn cached_random_substitutes_follow_mutations() {
+        let mut db = FunboyDatabase::from_path("test18.db").unwrap();
+
+        db.clear().unwrap();
+        db.set_legacy_access(TEST_GUILD_ID, false).unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(&["cat"]))
+            .unwrap();
+        assert_eq!(
+            db.get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "NOUN",
+                &mut rand::thread_rng()
+            )
+            .unwrap(),
+            "cat"
+        );
+
+        db.replace_substitute(TEST_GUILD_ID, TEST_USER_ID, "noun", "cat", "dog")
+            .unwrap();
+        assert_eq!(
+            db.get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "noun",
+                &mut rand::thread_rng()
+            )
+            .unwrap(),
+            "dog"
+        );
+
+        db.rename_template(TEST_GUILD_ID, TEST_USER_ID, "noun", "animal")
+            .unwrap();
+        assert!(db
+            .get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "noun",
+                &mut rand::thread_rng()
+            )
+            .is_err());
+        assert_eq!(
+            db.get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "animal",
+                &mut rand::thread_rng()
+            )
+            .unwrap(),
+            "dog"
+        );
+
+        let change = db.get_last_change(TEST_GUILD_ID, TEST_USER_ID).unwrap();
+        db.undo_change(TEST_GUILD_ID, &change.unwrap()).unwrap();
+        assert_eq!(
+            db.get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "noun",
+                &mut rand::thread_rng()
+            )
+            .unwrap(),
+            "dog"
+        );
+
+        db.insert_subs(LEGACY_GUILD_ID, TEST_USER_ID, "verb", Some(&["run"]))
+            .unwrap();
+        assert!(db
+            .get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "verb",
+                &mut rand::thread_rng()
+            )
+            .is_err());
+
+        db.set_legacy_access(TEST_GUILD_ID, true).unwrap();
+        assert_eq!(
+            db.get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "verb",
+                &mut rand::thread_rng()
+            )
+            .unwrap(),
+            "run"
+        );
+
+        db.remove_template(TEST_GUILD_ID, TEST_USER_ID, "noun")
+            .unwrap();
+        assert!(db
+            .get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "noun",
+                &mut rand::thread_rng()
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn draw_substitutes_from_deck() {
+        let mut db = FunboyDatabase::from_path("test20.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+        assert!(db
+            .set_deck_mode(TEST_GUILD_ID, TEST_USER_ID, "noun", true)
+            .unwrap());
+        assert!(!db
+            .set_deck_mode(TEST_GUILD_ID, TEST_USER_ID, "missing", true)
+            .unwrap());
+
+        let mut drawn: Vec<String> = (0..NOUNS.len() - 1)
+            .map(|_| {
+                db.get_random_subs(
+                    TEST_GUILD_ID,
+                    TEST_CHANNEL_ID,
+                    "noun",
+                    &mut rand::thread_rng(),
+                )
+                .unwrap()
+            })
+            .collect();
+
+        // Other channels draw from their own deck
+        db.get_random_subs(
+            TEST_GUILD_ID,
+            TEST_CHANNEL_ID + 1,
+            "noun",
+            &mut rand::thread_rng(),
+        )
+        .unwrap();
+
+        // Draws are remembered after the database is reopened
+        drop(db);
+        let mut db = FunboyDatabase::from_path("test20.db").unwrap();
+
+        drawn.push(
+            db.get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "noun",
+                &mut rand::thread_rng(),
+            )
+            .unwrap(),
+        );
+        drawn.sort_by_key(|sub| sub.to_lowercase());
+
+        let mut nouns = NOUNS.to_vec();
+        nouns.sort_by_key(|sub| sub.to_lowercase());
+        assert_eq!(drawn, nouns);
+
+        // The deck is shuffled once every substitute was drawn
+        assert!(!db
+            .get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "noun",
+                &mut rand::thread_rng()
+            )
+            .unwrap()
+            .is_empty());
+
+        db.set_deck_mode(TEST_GUILD_ID, TEST_USER_ID, "noun", false)
+            .unwrap();
+        let draws: i64 = db
+            .db
+            .query_row("SELECT COUNT(*) FROM deck_draws", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(draws, 0);
+    }
+
+    #[test]
+    fn forget_draws_of_removed_substitutes() {
+        let mut db = FunboyDatabase::from_path("test31.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+        db.set_deck_mode(TEST_GUILD_ID, TEST_USER_ID, "noun", true)
+            .unwrap();
+
+        let draws = |db: &FunboyDatabase| -> i64 {
+            db.db
+                .query_row("SELECT COUNT(*) FROM deck_draws", [], |row| row.get(0))
+                .unwrap()
+        };
+        let draw = |db: &mut FunboyDatabase| {
+            db.get_random_subs(
+                TEST_GUILD_ID,
+                TEST_CHANNEL_ID,
+                "noun",
+                &mut rand::thread_rng(),
+            )
+            .unwrap()
+        };
+
+        let drawn = draw(&mut db);
+        db.replace_substitute(TEST_GUILD_ID, TEST_USER_ID, "noun", &drawn, "replaced")
+            .unwrap();
+        assert_eq!(draws(&db), 0);
+
+        let drawn = draw(&mut db);
+        db.remove_sub(TEST_GUILD_ID, TEST_USER_ID, "noun", &drawn)
+            .unwrap();
+        assert_eq!(draws(&db), 0);
+
+        draw(&mut db);
+        db.remove_template(TEST_GUILD_ID, TEST_USER_ID, "noun")
+            .unwrap();
+        assert_eq!(draws(&db), 0);
+    }
+
+    #[test]
+    fn seeded_random_substitutes() {
+        let mut db = FunboyDatabase::from_path("test21.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", Some(NOUNS))
+            .unwrap();
+
+        let picks = |db: &mut FunboyDatabase, seed: u64| -> Vec<String> {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20)
+                .map(|_| {
+                    db.get_random_subs(TEST_GUILD_ID, TEST_CHANNEL_ID, "noun", &mut rng)
+                        .unwrap()
+                })
+                .collect()
+        };
+
+        assert_eq!(picks(&mut db, 42), picks(&mut db, 42));
+        assert_ne!(picks(&mut db, 42), picks(&mut db, 43));
+    }
+
+    #[test]
+    fn lint_guild_templates() {
+        let mut db = FunboyDatabase::from_path("test22.db").unwrap();
+
+        db.clear().unwrap();
+        db.set_legacy_access(TEST_GUILD_ID, true).unwrap();
+
+        db.insert_subs(LEGACY_GUILD_ID, TEST_USER_ID, "adj", Some(ADJECTIVES))
+            .unwrap();
+        db.insert_subs(
+            TEST_GUILD_ID,
+            TEST_USER_ID,
+            "sentence",
+            Some(&["^adj ^noun", "^missing"]),
+        )
+        .unwrap();
+        db.insert_subs(TEST_GUILD_ID, TEST_USER_ID, "noun", None)
+            .unwrap();
+
+        let report = db.lint_templates(TEST_GUILD_ID).unwrap();
+
+        assert_eq!(report.dangling.len(), 1);
+        assert_eq!(report.dangling[0].reference, "missing");
+        assert_eq!(report.empty, vec!["noun"]);
+        assert_eq!(report.unreachable, vec!["sentence"]);
+        assert!(report.cycles.is_empty());
+    }
+
+    #[test]
+    fn store_variables() {
+        let mut db = FunboyDatabase::from_path("test23.db").unwrap();
+
+        db.clear().unwrap();
+
+        assert!(db
+            .store_variable(TEST_GUILD_ID, GUILD_VARIABLE_USER_ID, "count", "1", 10, 25)
+            .unwrap());
+        assert!(db
+            .store_variable(TEST_GUILD_ID, TEST_USER_ID, "count", "2", 10, 25)
+            .unwrap());
+        assert!(db
+            .store_variable(TEST_GUILD_ID, GUILD_VARIABLE_USER_ID, "count", "3", 15, 25)
+            .unwrap());
+        assert!(!db
+            .store_variable(TEST_GUILD_ID, GUILD_VARIABLE_USER_ID, "other", "4", 1, 25)
+            .unwrap());
+        assert!(db
+            .store_variable(
+                LEGACY_GUILD_ID,
+                GUILD_VARIABLE_USER_ID,
+                "other",
+                "5",
+                25,
+                25
+            )
+            .unwrap());
+
+        let load = |user_id, name| db.load_variable(TEST_GUILD_ID, user_id, name).unwrap();
+        assert_eq!(load(GUILD_VARIABLE_USER_ID, "count"), Some("3".to_string()));
+        assert_eq!(load(TEST_USER_ID, "count"), Some("2".to_string()));
+        assert_eq!(load(GUILD_VARIABLE_USER_ID, "other"), None);
     }
 }
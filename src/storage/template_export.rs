@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::text_interpolator::defaults::{
+    escape_template_headers, extract_template, find_modifier, find_template_header,
+    halve_trailing_escapes, unescape_template_headers, MODIFIER_SEPARATOR, TEMPLATE_CARROT,
+};
+use crate::text_interpolator::{split_binding, split_modifiers};
+
+/// Version of the export format. Bumped whenever a change would break older imports.
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+/// Tracery has no weights so weighted substitutes are repeated, up to this many times.
+const TRACERY_WEIGHT_LIMIT: u32 = 100;
+const TRACERY_SYMBOL: char = '#';
+/// Tracery modifiers paired with the template modifier that does the same thing.
+const TRACERY_MODIFIERS: [(&str, &str); 3] = [("capitalize", "cap"), ("s", "plural"), ("a", "a")];
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TemplateExport {
+    pub version: u32,
+    pub templates: Vec<ExportedTemplate>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedTemplate {
+    pub name: String,
+    pub substitutes: Vec<ExportedSubstitute>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedSubstitute {
+    pub name: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// Number of templates and substitutes created by an import.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub templates: usize,
+    pub substitutes: usize,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl TemplateExport {
+    pub fn new(templates: Vec<ExportedTemplate>) -> TemplateExport {
+        TemplateExport {
+            version: EXPORT_FORMAT_VERSION,
+            templates,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<TemplateExport, String> {
+        let export: TemplateExport = serde_json::from_slice(json).map_err(|e| e.to_string())?;
+
+        if export.version > EXPORT_FORMAT_VERSION {
+            return Err(format!(
+                "export format version {} is newer than the supported version {}",
+                export.version, EXPORT_FORMAT_VERSION
+            ));
+        }
+
+        Ok(export)
+    }
+}
+
+/// Removes every character that isn't allowed in a template name.
+fn tracery_rule_to_template(rule: &str) -> String {
+    rule.chars().filter(|c| c.is_alphanumeric()).collect()
+}
+
+/// Rewrites every `#rule#` reference in a Tracery rule into a `^rule` template.
+///
+/// Tracery modifiers with a matching template modifier, such as `#rule.capitalize#`, are kept and
+/// the rest are dropped. Actions are kept as plain text.
+/// Template headers in plain text, such as the apostrophe in "don't", are escaped.
+fn tracery_to_substitute(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find(TRACERY_SYMBOL) {
+        let Some(length) = rest[start + 1..].find(TRACERY_SYMBOL) else {
+            break;
+        };
+
+        let end = start + 1 + length;
+        let mut reference = rest[start + 1..end].split('.');
+        let rule = reference.next().unwrap_or_default();
+
+        output.push_str(&escape_template_headers(&rest[..start]));
+        output.push(TEMPLATE_CARROT);
+        output.push_str(&tracery_rule_to_template(rule));
+
+        for tracery_modifier in reference {
+            if let Some((_, modifier)) = TRACERY_MODIFIERS
+                .iter()
+                .find(|(tracery, _)| *tracery == tracery_modifier)
+            {
+                output.push(MODIFIER_SEPARATOR);
+                output.push_str(modifier);
+            }
+        }
+
+        rest = &rest[end + 1..];
+
+        // Text or another reference directly after a reference would otherwise be read as part
+        // of the template name
+        if rest.starts_with(|c: char| c.is_alphanumeric() || c == TRACERY_SYMBOL) {
+            output.push(TEMPLATE_CARROT);
+        }
+    }
+
+    output.push_str(&escape_template_headers(rest));
+    output
+}
+
+/// Rewrites every template in a substitute into a Tracery `#rule#` reference.
+fn substitute_to_tracery(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+
+    for item in text.split_inclusive(char::is_whitespace) {
+        let word = item.trim_end();
+        let mut rest = word;
+
+        // A word can hold several templates, each read from the text after the one before
+        while find_template_header(rest).is_some() {
+            let template_split = extract_template(rest);
+
+            // Tracery has no equivalent to bindings so labels are dropped
+            let (_, suffix) = split_binding(template_split.suffix);
+
+            if template_split.template.is_empty() {
+                output.push_str(&unescape_template_headers(
+                    &rest[..rest.len() - suffix.len()],
+                ));
+                rest = suffix;
+                continue;
+            }
+
+            let (modifiers, suffix) = split_modifiers(suffix, find_modifier);
+
+            output.push_str(&unescape_template_headers(halve_trailing_escapes(
+                template_split.prefix,
+            )));
+            output.push(TRACERY_SYMBOL);
+            output.push_str(template_split.template);
+
+            for (name, _) in modifiers {
+                if let Some((tracery_modifier, _)) = TRACERY_MODIFIERS
+                    .iter()
+                    .find(|(_, modifier)| *modifier == name)
+                {
+                    output.push('.');
+                    output.push_str(tracery_modifier);
+                }
+            }
+
+            output.push(TRACERY_SYMBOL);
+            rest = suffix;
+        }
+
+        output.push_str(&unescape_template_headers(rest));
+        output.push_str(&item[word.len()..]);
+    }
+
+    output
+}
+
+impl TemplateExport {
+    /// Converts a Tracery grammar into templates.
+    ///
+    /// Rule names are stripped of characters templates can't contain and repeated rules are
+    /// merged into a single weighted substitute.
+    pub fn from_tracery(json: &[u8]) -> Result<TemplateExport, String> {
+        let grammar: BTreeMap<String, Value> =
+            serde_json::from_slice(json).map_err(|e| e.to_string())?;
+
+        let mut templates: Vec<ExportedTemplate> = Vec::new();
+
+        for (rule, value) in &grammar {
+            let name = tracery_rule_to_template(rule);
+
+            if name.is_empty() {
+                return Err(format!("rule \"{}\" has no valid template name", rule));
+            } else if templates.iter().any(|t| t.name.eq_ignore_ascii_case(&name)) {
+                return Err(format!(
+                    "rule \"{}\" has the same template name as another rule",
+                    rule
+                ));
+            }
+
+            let texts: Vec<&str> = match value {
+                Value::String(text) => vec![text.as_str()],
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| {
+                        item.as_str().ok_or(format!(
+                            "rule \"{}\" contains a value that isn't text",
+                            rule
+                        ))
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(format!("rule \"{}\" must be text or a list of text", rule)),
+            };
+
+            let mut substitutes: Vec<ExportedSubstitute> = Vec::new();
+
+            for text in texts {
+                let sub = tracery_to_substitute(text);
+
+                match substitutes.iter_mut().find(|s| s.name == sub) {
+                    Some(existing) => existing.weight = existing.weight.saturating_add(1),
+                    None => substitutes.push(ExportedSubstitute {
+                        name: sub,
+                        weight: 1,
+                    }),
+                }
+            }
+
+            templates.push(ExportedTemplate { name, substitutes });
+        }
+
+        Ok(TemplateExport::new(templates))
+    }
+
+    /// Converts templates into a Tracery grammar.
+    ///
+    /// Substitutes are repeated by their weight since Tracery picks each entry equally.
+    pub fn to_tracery(&self) -> serde_json::Result<String> {
+        let mut grammar: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+
+        for template in &self.templates {
+            let mut rules = Vec::new();
+
+            for sub in &template.substitutes {
+                let rule = substitute_to_tracery(&sub.name);
+
+                for _ in 0..sub.weight.min(TRACERY_WEIGHT_LIMIT) {
+                    rules.push(rule.clone());
+                }
+            }
+
+            grammar.insert(&template.name, rules);
+        }
+
+        serde_json::to_string_pretty(&grammar)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::text_interpolator::TextInterpolator;
+
+    use super::*;
+
+    #[test]
+    fn missing_weights_default_to_one() {
+        let export = TemplateExport::from_json(
+            br#"{"version": 1, "templates": [{"name": "noun", "substitutes": [{"name": "cat"}]}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(export.templates[0].substitutes[0].weight, 1);
+    }
+
+    #[test]
+    fn reject_newer_versions() {
+        assert!(TemplateExport::from_json(br#"{"version": 2, "templates": []}"#).is_err());
+        assert!(TemplateExport::from_json(b"not json").is_err());
+    }
+
+    #[test]
+    fn convert_tracery_references() {
+        assert_eq!(tracery_to_substitute("#noun# is #adj#"), "^noun is ^adj");
+        assert_eq!(tracery_to_substitute("#animal_name#s!"), "^animalname^s!");
+        assert_eq!(tracery_to_substitute("#noun.capitalize#."), "^noun.cap.");
+        assert_eq!(
+            tracery_to_substitute("#noun.s.capitalizeAll#y"),
+            "^noun.plural^y"
+        );
+        assert_eq!(tracery_to_substitute("a # sign"), "a # sign");
+        assert_eq!(tracery_to_substitute("#noun# don't"), "^noun don\\'t");
+        assert_eq!(tracery_to_substitute("#a#-#b#"), "^a-^b");
+        assert_eq!(tracery_to_substitute("#a##b#"), "^a^^b");
+        assert_eq!(tracery_to_substitute("\\'#noun#"), "\\\\\\'^noun");
+
+        assert_eq!(substitute_to_tracery("^noun is 'adj"), "#noun# is #adj#");
+        assert_eq!(substitute_to_tracery("^verb^ing  now."), "#verb#ing  now.");
+        assert_eq!(substitute_to_tracery("(^noun)"), "(#noun#)");
+        assert_eq!(substitute_to_tracery("^noun.a.upper^ish."), "#noun.a#ish.");
+        assert_eq!(substitute_to_tracery("don\\'t ^noun\\'s"), "don't #noun#'s");
+        assert_eq!(substitute_to_tracery("^a-^b"), "#a#-#b#");
+        assert_eq!(substitute_to_tracery("^a^^b"), "#a##b#");
+        assert_eq!(substitute_to_tracery("\\\\^noun"), "\\#noun#");
+    }
+
+    #[tokio::test]
+    async fn generate_adjacent_tracery_references() {
+        let provider = |template: &str| match template {
+            "a" => Some("x".to_string()),
+            "b" => Some("y".to_string()),
+            _ => None,
+        };
+
+        for (tracery, expected) in [("#a#-#b#", "x-y"), ("#a##b#", "xy"), ("#a#s#b#.", "xsy.")] {
+            let interpolation = TextInterpolator::default()
+                .interpolate(&tracery_to_substitute(tracery), &provider)
+                .await
+                .unwrap();
+
+            assert_eq!(interpolation.text, expected);
+            assert_eq!(interpolation.expansions.len(), 2);
+        }
+    }
+
+    #[test]
+    fn import_and_export_tracery() {
+        let export = TemplateExport::from_tracery(
+            br##"{"origin": ["#noun# is #adj#"], "noun": ["cat", "cat", "dog"], "adj": "fluffy"}"##,
+        )
+        .unwrap();
+
+        assert_eq!(export.templates.len(), 3);
+
+        let noun = export.templates.iter().find(|t| t.name == "noun").unwrap();
+        assert_eq!(noun.substitutes.len(), 2);
+        assert_eq!(noun.substitutes[0].weight, 2);
+
+        let grammar: BTreeMap<String, Vec<String>> =
+            serde_json::from_str(&export.to_tracery().unwrap()).unwrap();
+        assert_eq!(grammar["origin"], vec!["#noun# is #adj#"]);
+        assert_eq!(grammar["noun"], vec!["cat", "cat", "dog"]);
+
+        assert!(TemplateExport::from_tracery(br#"{"noun": [1]}"#).is_err());
+        assert!(TemplateExport::from_tracery(br#"{"a_b": [], "ab": []}"#).is_err());
+    }
+}
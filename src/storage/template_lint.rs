@@ -0,0 +1,225 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::template_database::SubstituteRecord;
+use super::template_references::{find_template_references, ReferenceKind};
+
+/// A template and every one of its substitutes.
+#[derive(Debug)]
+pub struct LintTemplate {
+    pub name: String,
+    pub substitutes: Vec<SubstituteRecord>,
+}
+
+/// A reference to a template that doesn't exist, left in the output as literal text.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DanglingReference {
+    pub template: String,
+    pub substitute_id: i32,
+    pub reference: String,
+}
+
+/// Problems found in a set of templates.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LintReport {
+    pub dangling: Vec<DanglingReference>,
+    /// Templates without a substitute that can be picked.
+    pub empty: Vec<String>,
+    /// Templates no other template references, which can only be used directly.
+    pub unreachable: Vec<String>,
+    /// Templates that reference each other in a loop, which fails generation when followed.
+    pub cycles: Vec<Vec<String>>,
+}
+
+impl LintReport {
+    pub fn is_empty(&self) -> bool {
+        self.dangling.is_empty()
+            && self.empty.is_empty()
+            && self.unreachable.is_empty()
+            && self.cycles.is_empty()
+    }
+}
+
+/// Finds strongly connected templates with Tarjan's algorithm.
+struct CycleFinder<'a> {
+    edges: &'a [BTreeSet<usize>],
+    index: Vec<Option<usize>>,
+    low_link: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    next_index: usize,
+    cycles: Vec<Vec<usize>>,
+}
+
+impl<'a> CycleFinder<'a> {
+    fn find(edges: &'a [BTreeSet<usize>]) -> Vec<Vec<usize>> {
+        let mut finder = CycleFinder {
+            edges,
+            index: vec![None; edges.len()],
+            low_link: vec![0; edges.len()],
+            on_stack: vec![false; edges.len()],
+            stack: Vec::new(),
+            next_index: 0,
+            cycles: Vec::new(),
+        };
+
+        for node in 0..edges.len() {
+            if finder.index[node].is_none() {
+                finder.connect(node);
+            }
+        }
+
+        finder.cycles
+    }
+
+    fn connect(&mut self, node: usize) {
+        self.index[node] = Some(self.next_index);
+        self.low_link[node] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+
+        for &next in self.edges[node].iter() {
+            match self.index[next] {
+                None => {
+                    self.connect(next);
+                    self.low_link[node] = self.low_link[node].min(self.low_link[next]);
+                }
+                Some(next_index) if self.on_stack[next] => {
+                    self.low_link[node] = self.low_link[node].min(next_index);
+                }
+                Some(_) => {}
+            }
+        }
+
+        if Some(self.low_link[node]) != self.index[node] {
+            return;
+        }
+
+        let mut component = Vec::new();
+        while let Some(member) = self.stack.pop() {
+            self.on_stack[member] = false;
+            component.push(member);
+            if member == node {
+                break;
+            }
+        }
+
+        if component.len() > 1 || self.edges[node].contains(&node) {
+            component.sort();
+            self.cycles.push(component);
+        }
+    }
+}
+
+/// Builds a reference graph from every substitute and reports anything that would keep the
+/// templates from generating as expected.
+///
+/// References to templates outside of the linted set, such as legacy templates, are only valid if
+/// they're listed in `other_templates` by their lowercase name.
+pub fn lint_templates(templates: &[LintTemplate], other_templates: &HashSet<String>) -> LintReport {
+    let lookup: HashMap<String, usize> = templates
+        .iter()
+        .enumerate()
+        .map(|(i, template)| (template.name.to_ascii_lowercase(), i))
+        .collect();
+
+    let mut report = LintReport::default();
+    let mut edges = vec![BTreeSet::new(); templates.len()];
+    let mut referenced = vec![false; templates.len()];
+
+    for (i, template) in templates.iter().enumerate() {
+        for sub in &template.substitutes {
+            for reference in find_template_references(&sub.name) {
+                match lookup.get(&reference.template.to_ascii_lowercase()) {
+                    Some(&target) => {
+                        referenced[target] |= target != i;
+
+                        // Substitutes from get_sub aren't evaluated again so they can't loop
+                        if reference.kind == ReferenceKind::Header {
+                            edges[i].insert(target);
+                        }
+                    }
+                    None if other_templates.contains(&reference.template.to_ascii_lowercase()) => {}
+                    None => report.dangling.push(DanglingReference {
+                        template: template.name.clone(),
+                        substitute_id: sub.id,
+                        reference: reference.template,
+                    }),
+                }
+            }
+        }
+
+        if !template.substitutes.iter().any(|sub| sub.weight > 0) {
+            report.empty.push(template.name.clone());
+        }
+    }
+
+    report.unreachable = templates
+        .iter()
+        .zip(referenced)
+        .filter(|(_, referenced)| !referenced)
+        .map(|(template, _)| template.name.clone())
+        .collect();
+
+    report.cycles = CycleFinder::find(&edges)
+        .into_iter()
+        .map(|cycle| {
+            cycle
+                .into_iter()
+                .map(|i| templates[i].name.clone())
+                .collect()
+        })
+        .collect();
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(name: &str, substitutes: &[(&str, u32)]) -> LintTemplate {
+        LintTemplate {
+            name: name.to_string(),
+            substitutes: substitutes
+                .iter()
+                .enumerate()
+                .map(|(i, (sub, weight))| SubstituteRecord {
+                    id: i as i32,
+                    name: sub.to_string(),
+                    template_id: 0,
+                    weight: *weight,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn lint_reference_graph() {
+        let templates = [
+            template("sentence", &[("^Noun ^verb^s ^nuon", 1), ("^legacy", 1)]),
+            template("noun", &[("cat", 1), ("{get_sub(\"noun\")}", 1)]),
+            template("verb", &[("run", 0)]),
+            template("a", &[("^b", 1)]),
+            template("b", &[("^a", 1), ("^b", 1)]),
+            template("self", &[("^self", 1)]),
+        ];
+        let other_templates = HashSet::from(["legacy".to_string()]);
+
+        let report = lint_templates(&templates, &other_templates);
+
+        assert_eq!(
+            report.dangling,
+            vec![DanglingReference {
+                template: "sentence".to_string(),
+                substitute_id: 0,
+                reference: "nuon".to_string(),
+            }]
+        );
+        assert_eq!(report.empty, vec!["verb"]);
+        assert_eq!(report.unreachable, vec!["sentence", "self"]);
+        assert_eq!(report.cycles, vec![vec!["a", "b"], vec!["self"]]);
+        assert!(!report.is_empty());
+        assert!(lint_templates(&[], &other_templates).is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+use std::ops::Range;
+
+use crate::fsl_interpreter::find_sub_calls;
+use crate::text_interpolator::defaults::{extract_template, find_template_header};
+
+const CODE_START: char = '{';
+const CODE_END: char = '}';
+
+/// How a substitute refers to a template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReferenceKind {
+    /// A template header such as **^template**, substituted while interpolating.
+    Header,
+    /// A `get_sub("template")` call in embedded code, substituted while evaluating the code.
+    SubCall,
+}
+
+/// A template referenced by a substitute.
+#[derive(Debug, PartialEq)]
+pub struct TemplateReference {
+    pub template: String,
+    pub kind: ReferenceKind,
+    /// Byte range of the template name in the substitute.
+    pub span: Range<usize>,
+}
+
+/// Finds every template referenced with a template header, the same way the interpolator reads
+/// each word.
+fn find_header_references(text: &str, references: &mut Vec<TemplateReference>) {
+    let mut offset = 0;
+
+    for item in text.split_inclusive(char::is_whitespace) {
+        let word = item.trim_end();
+        let template_split = extract_template(word);
+
+        if !template_split.template.is_empty() {
+            if let Some(header) = find_template_header(word) {
+                let start = offset + header + 1;
+
+                references.push(TemplateReference {
+                    template: template_split.template.to_string(),
+                    kind: ReferenceKind::Header,
+                    span: start..start + template_split.template.len(),
+                });
+            }
+        }
+
+        offset += item.len();
+    }
+}
+
+/// Finds every `get_sub` call in embedded code. Code is split around nested blocks just like it
+/// is when evaluated.
+fn find_code_references(text: &str, references: &mut Vec<TemplateReference>) {
+    let mut depth = 0;
+    let mut code_start = 0;
+
+    for (i, c) in text.char_indices() {
+        if c != CODE_START && c != CODE_END {
+            continue;
+        }
+
+        if depth > 0 {
+            for (template, span) in find_sub_calls(&text[code_start..i]) {
+                references.push(TemplateReference {
+                    template,
+                    kind: ReferenceKind::SubCall,
+                    span: code_start + span.start..code_start + span.end,
+                });
+            }
+        }
+
+        match c {
+            CODE_START => depth += 1,
+            _ if depth > 0 => depth -= 1,
+            _ => {}
+        }
+
+        code_start = i + 1;
+    }
+}
+
+/// Finds every template referenced by a substitute, in the order they appear.
+pub fn find_template_references(text: &str) -> Vec<TemplateReference> {
+    let mut references = Vec::new();
+
+    find_header_references(text, &mut references);
+    find_code_references(text, &mut references);
+
+    references.sort_by_key(|reference| reference.span.start);
+    references
+}
+
+/// Rewrites every exact reference to the old template so it refers to the new template instead.
+///
+/// Templates that only start with the old name, such as **^nouns** when renaming **noun**, are
+/// left alone.
+pub fn rename_template_references(text: &str, old: &str, new: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut last_end = 0;
+
+    for reference in find_template_references(text) {
+        if reference.span.start < last_end || !reference.template.eq_ignore_ascii_case(old) {
+            continue;
+        }
+
+        output.push_str(&text[last_end..reference.span.start]);
+        output.push_str(new);
+        last_end = reference.span.end;
+    }
+
+    output.push_str(&text[last_end..]);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_references() {
+        let text = "(^noun.cap) {get_sub(\"verb\")} 'adj#1's \\^escaped";
+        let templates: Vec<String> = find_template_references(text)
+            .into_iter()
+            .map(|reference| {
+                assert_eq!(
+                    text[reference.span].to_lowercase(),
+                    reference.template.to_lowercase()
+                );
+                reference.template
+            })
+            .collect();
+
+        assert_eq!(templates, vec!["noun", "verb", "adj"]);
+    }
+
+    #[test]
+    fn rename_exact_references() {
+        assert_eq!(
+            rename_template_references("^noun ^nouns2 ^nounphrase ^Noun^s", "noun", "thing"),
+            "^thing ^nouns2 ^nounphrase ^thing^s"
+        );
+        assert_eq!(
+            rename_template_references("don\\'t \\^noun 'noun's", "noun", "thing"),
+            "don\\'t \\^noun 'thing's"
+        );
+        assert_eq!(
+            rename_template_references(
+                "{add(get_sub( \"noun\" ), get_sub(\"nouns\"))} get_sub(\"noun\")",
+                "noun",
+                "thing"
+            ),
+            "{add(get_sub( \"thing\" ), get_sub(\"nouns\"))} get_sub(\"noun\")"
+        );
+        assert_eq!(
+            rename_template_references("{repeat(2, {get_sub(\"noun\")})}", "noun", "thing"),
+            "{repeat(2, {get_sub(\"thing\")})}"
+        );
+    }
+}
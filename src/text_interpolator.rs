@@ -6,13 +6,22 @@
 //! template, extract it, and then map it to it's substitute.
 //!
 //! It also supports nested templates requiring recursion to reach a valid substitute.
+//! Substitutes are requested from a [`SubstituteProvider`] one template at a time so slow sources,
+//! such as a shared database, are only waited on while a single template is looked up.
 
+pub mod choices;
 pub mod defaults;
 
+use async_recursion::async_recursion;
 use core::fmt;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::future::{self, Future};
+use std::ops::Range;
 
-use defaults::TEMPLATE_HEADERS;
+use defaults::{
+    find_removed_escapes, halve_trailing_escapes, remove_escapes, BINDING_SEPARATOR,
+    MODIFIER_SEPARATOR, TEMPLATE_HEADERS,
+};
 
 #[derive(Debug, Clone)]
 pub struct NestedTemplateLoopError;
@@ -23,6 +32,112 @@ impl fmt::Display for NestedTemplateLoopError {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct NestingDepthError {
+    pub max_depth: usize,
+}
+
+impl fmt::Display for NestingDepthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "templates were nested more than {} levels deep",
+            self.max_depth
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExpansionLimitError {
+    pub max_expansions: usize,
+}
+
+impl fmt::Display for ExpansionLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "templates were substituted more than {} times",
+            self.max_expansions
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputSizeError {
+    pub max_output_bytes: usize,
+}
+
+impl fmt::Display for OutputSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "output was larger than {} bytes", self.max_output_bytes)
+    }
+}
+
+/// Any error that stops text from being interpolated.
+#[derive(Debug, Clone)]
+pub enum InterpolationError {
+    NestedTemplateLoop(NestedTemplateLoopError),
+    NestingDepth(NestingDepthError),
+    ExpansionLimit(ExpansionLimitError),
+    OutputSize(OutputSizeError),
+}
+
+impl fmt::Display for InterpolationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpolationError::NestedTemplateLoop(e) => e.fmt(f),
+            InterpolationError::NestingDepth(e) => e.fmt(f),
+            InterpolationError::ExpansionLimit(e) => e.fmt(f),
+            InterpolationError::OutputSize(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<NestedTemplateLoopError> for InterpolationError {
+    fn from(e: NestedTemplateLoopError) -> Self {
+        InterpolationError::NestedTemplateLoop(e)
+    }
+}
+
+impl From<NestingDepthError> for InterpolationError {
+    fn from(e: NestingDepthError) -> Self {
+        InterpolationError::NestingDepth(e)
+    }
+}
+
+impl From<ExpansionLimitError> for InterpolationError {
+    fn from(e: ExpansionLimitError) -> Self {
+        InterpolationError::ExpansionLimit(e)
+    }
+}
+
+impl From<OutputSizeError> for InterpolationError {
+    fn from(e: OutputSizeError) -> Self {
+        InterpolationError::OutputSize(e)
+    }
+}
+
+/// Limits that stop deeply nested or rapidly growing templates from stalling interpolation.
+#[derive(Debug, Clone, Copy)]
+pub struct InterpolationLimits {
+    /// How many templates deep substitutes may be nested.
+    pub max_depth: usize,
+    /// How many templates may be substituted in a single interpolation.
+    pub max_expansions: usize,
+    /// How large the output may grow in bytes.
+    pub max_output_bytes: usize,
+}
+
+impl Default for InterpolationLimits {
+    fn default() -> Self {
+        InterpolationLimits {
+            max_depth: 32,
+            max_expansions: 2048,
+            max_output_bytes: 64 * 1024,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct TemplateSplit<'a> {
     pub prefix: &'a str,
@@ -34,13 +149,167 @@ pub struct TemplateSplit<'a> {
 pub type IsTemplateFn = fn(&str) -> bool;
 /// A function that splits a templated string into its suffix, prefix, and template components.
 pub type ExtractTemplateFn = fn(&str) -> TemplateSplit;
+/// A function that transforms a substitute, such as capitalizing it.
+pub type ModifierFn = fn(&str) -> String;
+/// A function that finds the modifier with the given name.
+pub type FindModifierFn = fn(&str) -> Option<ModifierFn>;
+
+/// A substitute returned when mapping a template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Substitute {
+    pub text: String,
+    /// Identifies where the substitute came from, if it has an identity.
+    pub id: Option<i64>,
+}
+
+impl From<String> for Substitute {
+    fn from(text: String) -> Self {
+        Substitute { text, id: None }
+    }
+}
+
+/// Maps templates to substitutes.
+///
+/// Any function mapping a template to an optional substitute is a provider, providers that need
+/// to wait on something such as a database lock implement the trait directly.
+pub trait SubstituteProvider: Sync {
+    /// Returns a substitute for the template or None if the template doesn't exist.
+    fn get_substitute(&self, template: &str) -> impl Future<Output = Option<Substitute>> + Send;
+}
+
+impl<F, S> SubstituteProvider for F
+where
+    F: Fn(&str) -> Option<S> + Sync,
+    S: Into<Substitute>,
+{
+    fn get_substitute(&self, template: &str) -> impl Future<Output = Option<Substitute>> + Send {
+        future::ready(self(template).map(Into::into))
+    }
+}
+
+/// A template that was substituted while interpolating text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub template: String,
+    pub substitute_id: Option<i64>,
+    /// The substitute after its nested templates were expanded and modifiers were applied.
+    pub output: String,
+    /// True if the substitute was reused from an earlier template with the same label.
+    pub bound: bool,
+    /// Byte range of the output in the interpolated text.
+    pub span: Range<usize>,
+    pub children: Vec<Expansion>,
+}
+
+impl Expansion {
+    /// Moves the expansion and every expansion nested inside of it.
+    fn map_spans(&mut self, map: &impl Fn(usize) -> usize) {
+        self.span = map(self.span.start)..map(self.span.end);
+
+        for child in &mut self.children {
+            child.map_spans(map);
+        }
+    }
+}
+
+/// Interpolated text along with every template substituted to create it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interpolation {
+    pub text: String,
+    pub expansions: Vec<Expansion>,
+}
+
+/// Splits the label at the start of a template's suffix from the rest of the suffix.
+///
+/// A template header directly after the label separates it from the suffix and is removed.
+///
+/// # Examples
+///
+/// ```
+/// use text_interpolator::split_binding;
+///
+/// assert_eq!(split_binding("#1.cap"), (Some("1"), ".cap"));
+/// assert_eq!(split_binding("#hero^s"), (Some("hero"), "s"));
+/// assert_eq!(split_binding("#!"), (None, "#!"));
+/// ```
+pub fn split_binding(suffix: &str) -> (Option<&str>, &str) {
+    let Some(binding) = suffix.strip_prefix(BINDING_SEPARATOR) else {
+        return (None, suffix);
+    };
+
+    let label_end = binding
+        .find(|c: char| !c.is_alphanumeric())
+        .unwrap_or(binding.len());
+
+    if label_end == 0 {
+        return (None, suffix);
+    }
+
+    let mut rest = &binding[label_end..];
+
+    if rest.starts_with(TEMPLATE_HEADERS) {
+        rest = &rest[1..];
+    }
+
+    (Some(&binding[..label_end]), rest)
+}
+
+/// Splits the modifiers at the start of a template's suffix from the rest of the suffix.
+///
+/// Only known modifiers are split so **'template.** keeps its period. A template header directly
+/// after the modifiers separates them from the suffix and is removed.
+///
+/// # Examples
+///
+/// ```
+/// use text_interpolator::split_modifiers;
+/// use text_interpolator::defaults::find_modifier;
+///
+/// let (modifiers, suffix) = split_modifiers(".cap.plural^ish.", find_modifier);
+///
+/// assert_eq!(modifiers.len(), 2);
+/// assert_eq!(suffix, "ish.");
+/// ```
+pub fn split_modifiers(
+    suffix: &str,
+    find_modifier: FindModifierFn,
+) -> (Vec<(&str, ModifierFn)>, &str) {
+    let mut modifiers = Vec::new();
+    let mut rest = suffix;
+
+    while let Some(modifier) = rest.strip_prefix(MODIFIER_SEPARATOR) {
+        let name_end = modifier
+            .find(|c: char| !c.is_alphanumeric())
+            .unwrap_or(modifier.len());
+        let name = &modifier[..name_end];
+
+        match find_modifier(name) {
+            Some(modifier_fn) => {
+                modifiers.push((name, modifier_fn));
+                rest = &modifier[name_end..];
+            }
+            None => break,
+        }
+    }
+
+    if !modifiers.is_empty() && rest.starts_with(TEMPLATE_HEADERS) {
+        rest = &rest[1..];
+    }
+
+    (modifiers, rest)
+}
 
 #[derive(Debug)]
 pub struct TextInterpolator {
     #[allow(dead_code)]
     pub is_template: IsTemplateFn,
     pub extract_template: ExtractTemplateFn,
+    pub find_modifier: FindModifierFn,
+    pub limits: InterpolationLimits,
     template_set: HashSet<String>,
+    expansions: usize,
+    /// Substitutes chosen for labeled templates, reused every time the label appears again.
+    bindings: HashMap<String, Substitute>,
 }
 
 impl Default for TextInterpolator {
@@ -57,7 +326,11 @@ impl Default for TextInterpolator {
         TextInterpolator {
             is_template: defaults::is_template,
             extract_template: defaults::extract_template,
+            find_modifier: defaults::find_modifier,
+            limits: InterpolationLimits::default(),
             template_set: HashSet::new(),
+            expansions: 0,
+            bindings: HashMap::new(),
         }
     }
 }
@@ -76,87 +349,284 @@ impl TextInterpolator {
     ///         prefix: "",
     ///         template: "example",
     ///         suffix: "",
-    ///     }
+    ///     },
+    ///     |_| None,
     /// );
     /// ```
     #[allow(dead_code)]
-    pub fn new(is_template: IsTemplateFn, extract_template: ExtractTemplateFn) -> Self {
+    pub fn new(
+        is_template: IsTemplateFn,
+        extract_template: ExtractTemplateFn,
+        find_modifier: FindModifierFn,
+    ) -> Self {
         TextInterpolator {
             is_template,
             extract_template,
+            find_modifier,
+            limits: InterpolationLimits::default(),
             template_set: HashSet::new(),
+            expansions: 0,
+            bindings: HashMap::new(),
         }
     }
 
-    /// Reconstructs a string by mapping any templated inner strings to a substitute.
+    /// Reconstructs a string by mapping any templated inner strings to a substitute, recording
+    /// every template that was substituted and the expansions nested inside of it.
+    ///
+    /// Only templated words are replaced, all whitespace between words is kept as is. Escaped
+    /// template headers such as \\' are output without the escape character.
+    ///
+    /// Modifiers following a template, such as **'template.cap**, are applied to the substitute in
+    /// order.
     ///
-    /// Will return an error if self referential templates are found to prevent infinite recursion.
+    /// Templates labeled with a binding, such as **'template#1**, are substituted once and every
+    /// later use of the same template and label reuses that substitute. Modifiers are placed after
+    /// the label: **'template#1.cap**
+    ///
+    /// Will return an error if self referential templates are found to prevent infinite recursion
+    /// or if any of the interpolator's limits are exceeded.
     ///
     /// # Examples
     ///
     /// ```
     /// use text_interpolator::TextInterpolator;
     ///
+    /// # tokio::runtime::Runtime::new().unwrap().block_on(async {
     /// let mut text_interpolator = TextInterpolator::default();
     ///
-    /// let text = text_interpolator.interp(
+    /// let interpolation = text_interpolator.interpolate(
     ///     "The word 'template will be replaced with substitute",
-    ///     &|s| {
+    ///     &|s: &str| {
     ///         if s == "template" {
     ///             Some("substitute".to_string())
     ///         } else {
     ///             None
     ///         }
     ///     },
+    /// ).await;
+    ///
+    /// assert_eq!(
+    ///     interpolation.unwrap().text,
+    ///     "The word substitute will be replaced with substitute"
     /// );
     ///
-    /// assert_eq!(text.unwrap(), "The word substitute will be replaced with substitute");
+    /// let interpolation = text_interpolator
+    ///     .interpolate("'greeting", &|s: &str| match s {
+    ///         "greeting" => Some("hello 'name".to_string()),
+    ///         "name" => Some("world".to_string()),
+    ///         _ => None,
+    ///     })
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// assert_eq!(interpolation.text, "hello world");
+    /// assert_eq!(interpolation.expansions[0].children[0].template, "name");
+    /// # });
     /// ```
-    pub fn interp(
+    pub async fn interpolate(
+        &mut self,
+        text: &str,
+        provider: &impl SubstituteProvider,
+    ) -> Result<Interpolation, InterpolationError> {
+        self.bindings.clear();
+        self.continue_interpolation(text, provider).await
+    }
+
+    /// Reconstructs more text of the same generation like [`TextInterpolator::interpolate`],
+    /// reusing the substitutes already bound to labels instead of forgetting them.
+    pub async fn continue_interpolation(
+        &mut self,
+        text: &str,
+        provider: &impl SubstituteProvider,
+    ) -> Result<Interpolation, InterpolationError> {
+        self.template_set.clear();
+        self.expansions = 0;
+
+        let mut interpolation = self.interp_templates(text, provider, 0).await?;
+
+        // Every escape character removed moves the text after it one byte closer to the start.
+        let escapes = find_removed_escapes(interpolation.text.char_indices(), &TEMPLATE_HEADERS);
+        let unescaped = |i: usize| i - escapes.partition_point(|escape| *escape < i);
+
+        for expansion in &mut interpolation.expansions {
+            expansion.map_spans(&unescaped);
+        }
+
+        interpolation.text = remove_escapes(&interpolation.text, &escapes);
+        Ok(interpolation)
+    }
+
+    /// Replaces templates while leaving escaped template headers untouched so nested substitutes
+    /// are only unescaped once.
+    #[async_recursion]
+    async fn interp_templates<P: SubstituteProvider>(
         &mut self,
         text: &str,
-        map: &impl Fn(&str) -> Option<String>,
-    ) -> Result<String, NestedTemplateLoopError> {
+        provider: &P,
+        depth: usize,
+    ) -> Result<Interpolation, InterpolationError> {
+        if depth > self.limits.max_depth {
+            return Err(NestingDepthError {
+                max_depth: self.limits.max_depth,
+            }
+            .into());
+        }
+
         // String will likely be at least as long as input
         let mut output = String::with_capacity(text.len());
+        let mut expansions = Vec::new();
+        let mut rest = text;
+
+        while !rest.is_empty() {
+            let word_start = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            output.push_str(&rest[..word_start]);
+            rest = &rest[word_start..];
+
+            let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let item = &rest[..word_end];
+            rest = &rest[word_end..];
+
+            if item.is_empty() {
+                continue;
+            }
 
-        for item in text.split_whitespace() {
             let template_split = (self.extract_template)(item);
+            let (binding, suffix) = split_binding(template_split.suffix);
+            let binding = binding.map(|label| {
+                format!(
+                    "{}{}{}",
+                    template_split.template.to_lowercase(),
+                    BINDING_SEPARATOR,
+                    label
+                )
+            });
+
+            let bound_substitute = binding
+                .as_ref()
+                .and_then(|binding| self.bindings.get(binding))
+                .cloned();
+
+            let expansion = match bound_substitute {
+                Some(substitute) => Some(Expansion {
+                    template: template_split.template.to_string(),
+                    substitute_id: substitute.id,
+                    output: substitute.text,
+                    bound: true,
+                    span: 0..0,
+                    children: Vec::new(),
+                }),
+                None => match provider.get_substitute(template_split.template).await {
+                    Some(substitute) => {
+                        // Template names are case insensitive, so **^Noun** inside of **noun**
+                        // is still a loop.
+                        let template_key = template_split.template.to_ascii_lowercase();
+                        if !self.template_set.insert(template_key.clone()) {
+                            self.template_set.clear();
+                            return Err(NestedTemplateLoopError.into());
+                        }
+
+                        self.expansions += 1;
+                        if self.expansions > self.limits.max_expansions {
+                            return Err(ExpansionLimitError {
+                                max_expansions: self.limits.max_expansions,
+                            }
+                            .into());
+                        }
+
+                        let Substitute { mut text, id } = substitute;
+                        let mut children = Vec::new();
+
+                        if self.contains_template(&text) {
+                            let nested = self.interp_templates(&text, provider, depth + 1).await?;
+                            text = nested.text;
+                            children = nested.expansions;
+                        }
+
+                        self.template_set.remove(&template_key);
+
+                        if let Some(binding) = binding {
+                            self.bindings.insert(
+                                binding,
+                                Substitute {
+                                    text: text.clone(),
+                                    id,
+                                },
+                            );
+                        }
 
-            match map(template_split.template) {
-                Some(substitute) => {
-                    if !self
-                        .template_set
-                        .insert(template_split.template.to_string())
-                    {
-                        self.template_set.clear();
-                        return Err(NestedTemplateLoopError);
+                        Some(Expansion {
+                            template: template_split.template.to_string(),
+                            substitute_id: id,
+                            output: text,
+                            bound: false,
+                            span: 0..0,
+                            children,
+                        })
                     }
+                    None => None,
+                },
+            };
 
-                    let mut substitution = substitute;
+            match expansion {
+                Some(mut expansion) => {
+                    let (modifiers, suffix) = split_modifiers(suffix, self.find_modifier);
 
-                    if self.contains_template(&substitution) {
-                        substitution = self.interp(&substitution, map)?;
+                    for (_, modifier) in modifiers {
+                        expansion.output = modifier(&expansion.output);
                     }
 
-                    self.template_set.remove(template_split.template);
+                    // The header is gone, so pairs of escape characters before it are collapsed now
+                    output.push_str(halve_trailing_escapes(template_split.prefix));
+                    let start = output.len();
+                    output.push_str(&expansion.output);
 
-                    output.push_str(template_split.prefix);
-                    output.push_str(&substitution);
-                    output.push_str(template_split.suffix);
-                    output.push(' ');
+                    // Nested expansions were placed in the substitute, which now starts at `start`.
+                    expansion.map_spans(&|i| start + i);
+                    expansion.span = start..start + expansion.output.len();
+                    expansions.push(expansion);
+
+                    rest = self.rescan_suffix(text, rest, suffix, &mut output);
                 }
                 None => {
-                    output.push_str(item);
-                    output.push(' ');
+                    output.push_str(&item[..item.len() - suffix.len()]);
+                    rest = self.rescan_suffix(text, rest, suffix, &mut output);
+                }
+            }
+
+            if output.len() > self.limits.max_output_bytes {
+                return Err(OutputSizeError {
+                    max_output_bytes: self.limits.max_output_bytes,
                 }
+                .into());
             }
         }
 
-        // Remove trailing space
-        output.pop();
+        Ok(Interpolation {
+            text: output,
+            expansions,
+        })
+    }
 
-        Ok(output)
+    /// Returns the text left to interpolate after a word's suffix.
+    ///
+    /// The suffix ends where `rest` starts, so a suffix containing another template, such as
+    /// **-^last** in **^first-^last**, is read again as the next word. Any other suffix is output
+    /// as is.
+    fn rescan_suffix<'a>(
+        &self,
+        text: &'a str,
+        rest: &'a str,
+        suffix: &str,
+        output: &mut String,
+    ) -> &'a str {
+        if self.contains_template(suffix) {
+            &text[text.len() - rest.len() - suffix.len()..]
+        } else {
+            output.push_str(suffix);
+            rest
+        }
     }
 
     /// Returns true if the string contains a template
@@ -179,6 +649,8 @@ impl TextInterpolator {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     use super::*;
 
     fn map_template(template: &str) -> Option<String> {
@@ -201,26 +673,34 @@ mod tests {
         }
     }
 
-    #[test]
-    fn interpolate_non_templated_text() {
+    #[tokio::test]
+    async fn interpolate_non_templated_text() {
         let mut interpolator = TextInterpolator::default();
 
         let text: String = String::from(
             "This is an example of a basic input with no templates to be substituted.",
         );
-        let interpolated_text = interpolator.interp(&text, &map_template).unwrap();
+        let interpolated_text = interpolator
+            .interpolate(&text, &map_template)
+            .await
+            .unwrap()
+            .text;
 
         dbg!(&interpolated_text);
 
         assert_eq!(&text, &interpolated_text);
     }
 
-    #[test]
-    fn interpolate_templated_text() {
+    #[tokio::test]
+    async fn interpolate_templated_text() {
         let mut interpolator = TextInterpolator::default();
 
         let text: String = String::from("A 'adj 'noun will always 'verb in the morning.");
-        let interpolated_text = interpolator.interp(&text, &map_template).unwrap();
+        let interpolated_text = interpolator
+            .interpolate(&text, &map_template)
+            .await
+            .unwrap()
+            .text;
 
         dbg!(&interpolated_text);
 
@@ -229,12 +709,16 @@ mod tests {
         assert!(!interpolated_text.contains("'verb"));
     }
 
-    #[test]
-    fn interpolate_templated_text_2() {
+    #[tokio::test]
+    async fn interpolate_templated_text_2() {
         let mut interpolator = TextInterpolator::default();
 
         let text: String = String::from("I'm 'verb'ing some 'adj 'noun's right now.");
-        let interpolated_text = interpolator.interp(&text, &map_template).unwrap();
+        let interpolated_text = interpolator
+            .interpolate(&text, &map_template)
+            .await
+            .unwrap()
+            .text;
 
         dbg!(&interpolated_text);
 
@@ -243,70 +727,382 @@ mod tests {
         assert!(!interpolated_text.contains("'noun"));
     }
 
-    #[test]
-    fn interpolated_nested_templated_text() {
+    #[tokio::test]
+    async fn interpolated_nested_templated_text() {
         let mut interpolator = TextInterpolator::default();
 
         let text: String = String::from("'sentence");
-        let interpolated_text = interpolator.interp(&text, &map_template);
+        let interpolated_text = interpolator.interpolate(&text, &map_template).await;
         dbg!(&interpolated_text);
 
-        assert!(!interpolator.contains_template(&interpolated_text.unwrap()));
+        assert!(!interpolator.contains_template(&interpolated_text.unwrap().text));
     }
 
-    #[test]
-    fn interpolated_double_nested_templated_text() {
+    #[tokio::test]
+    async fn interpolated_double_nested_templated_text() {
         let mut interpolator = TextInterpolator::default();
 
         let text: String = String::from("'paragraph");
-        let interpolated_text = interpolator.interp(&text, &map_template);
+        let interpolated_text = interpolator.interpolate(&text, &map_template).await;
 
         dbg!(&interpolated_text);
 
-        assert!(!interpolator.contains_template(&interpolated_text.unwrap()));
+        assert!(!interpolator.contains_template(&interpolated_text.unwrap().text));
     }
 
-    #[test]
-    fn interpolated_double_nested_templated_text_with_prefix_and_suffix() {
+    #[tokio::test]
+    async fn interpolated_double_nested_templated_text_with_prefix_and_suffix() {
         let mut interpolator = TextInterpolator::default();
 
         let text: String = String::from("My Story:'paragraph...");
 
-        let interpolated_text = interpolator.interp(&text, &map_template);
+        let interpolated_text = interpolator.interpolate(&text, &map_template).await;
 
         dbg!(&interpolated_text);
 
-        assert!(!interpolator.contains_template(&interpolated_text.unwrap()));
+        assert!(!interpolator.contains_template(&interpolated_text.unwrap().text));
     }
 
-    #[test]
-    fn missing_template() {
+    #[tokio::test]
+    async fn missing_template() {
         let mut interpolator = TextInterpolator::default();
 
         let text: String = String::from("'klsfjkaejfaeskfjl");
 
-        let interpolated_text = interpolator.interp(&text, &map_template);
+        let interpolated_text = interpolator.interpolate(&text, &map_template).await;
 
         dbg!(&interpolated_text);
 
-        assert_eq!("'klsfjkaejfaeskfjl", &interpolated_text.unwrap());
+        assert_eq!("'klsfjkaejfaeskfjl", &interpolated_text.unwrap().text);
     }
 
-    #[test]
-    fn missing_nested_template() {
+    #[tokio::test]
+    async fn missing_nested_template() {
         let mut interpolator = TextInterpolator::default();
-        let interp_text = interpolator.interp("'nonexistantnest", &map_template);
+        let interp_text = interpolator
+            .interpolate("'nonexistantnest", &map_template)
+            .await;
         dbg!(interp_text.unwrap());
     }
 
-    #[test]
-    fn infinite_self_recursion() {
+    #[tokio::test]
+    async fn infinite_self_recursion() {
         let mut interpolator = TextInterpolator::default();
 
         let text: String = String::from("'infinite");
 
-        let interpolated_text = interpolator.interp(&text, &map_template);
+        let interpolated_text = interpolator.interpolate(&text, &map_template).await;
 
         assert!(&interpolated_text.is_err());
     }
+
+    #[tokio::test]
+    async fn detect_loops_regardless_of_case() {
+        let mut interpolator = TextInterpolator::default();
+        let count = AtomicUsize::new(0);
+
+        let interpolated_text = interpolator
+            .interpolate("'loop", &|template: &str| {
+                count.fetch_add(1, Ordering::Relaxed);
+                template
+                    .eq_ignore_ascii_case("loop")
+                    .then(|| "'LOOP".to_string())
+            })
+            .await;
+
+        assert!(interpolated_text.is_err());
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn preserve_newlines() {
+        let mut interpolator = TextInterpolator::default();
+
+        let text = "Roses are 'adj,\nviolets are 'adj.\n\n";
+        let interpolated_text = interpolator
+            .interpolate(text, &map_template)
+            .await
+            .unwrap()
+            .text;
+
+        assert_eq!(
+            "Roses are aggrivating,\nviolets are aggrivating.\n\n",
+            interpolated_text
+        );
+    }
+
+    #[tokio::test]
+    async fn preserve_tabs_and_repeated_spaces() {
+        let mut interpolator = TextInterpolator::default();
+
+        let text = "  \tfn 'verb()   {\n\t\treturn 'noun;\n\t}";
+        let interpolated_text = interpolator
+            .interpolate(text, &map_template)
+            .await
+            .unwrap()
+            .text;
+
+        assert_eq!(
+            "  \tfn run()   {\n\t\treturn place;\n\t}",
+            interpolated_text
+        );
+    }
+
+    #[tokio::test]
+    async fn preserve_whitespace_in_substitutes() {
+        let mut interpolator = TextInterpolator::default();
+
+        let interpolated_text = interpolator
+            .interpolate("'poem", &|template: &str| match template {
+                "poem" => Some("line one\n  line 'two".to_string()),
+                "two" => Some("two\tand three".to_string()),
+                _ => None,
+            })
+            .await
+            .unwrap()
+            .text;
+
+        assert_eq!("line one\n  line two\tand three", interpolated_text);
+    }
+
+    #[tokio::test]
+    async fn escaped_template_headers() {
+        let mut interpolator = TextInterpolator::default();
+
+        let interpolated_text = interpolator
+            .interpolate(
+                "I don\\'t like 'noun\\'s \\'adj",
+                &|template: &str| match template {
+                    "noun" => Some("my friend\\'s".to_string()),
+                    "t" | "adj" => Some("mangled".to_string()),
+                    _ => None,
+                },
+            )
+            .await
+            .unwrap()
+            .text;
+
+        assert_eq!("I don't like my friend's's 'adj", interpolated_text);
+    }
+
+    #[tokio::test]
+    async fn escaped_escape_characters() {
+        let mut interpolator = TextInterpolator::default();
+
+        let interpolation = interpolator
+            .interpolate("a \\\\'noun b", &map_template)
+            .await
+            .unwrap();
+
+        assert_eq!("a \\place b", interpolation.text);
+        assert_eq!(3..8, interpolation.expansions[0].span);
+
+        let interpolation = interpolator
+            .interpolate("a \\\\\\'noun 'noun", &map_template)
+            .await
+            .unwrap();
+
+        assert_eq!("a \\'noun place", interpolation.text);
+        assert_eq!(9..14, interpolation.expansions[0].span);
+
+        let interpolation = interpolator
+            .interpolate("C:\\\\Users \\\\'missing", &map_template)
+            .await
+            .unwrap();
+
+        assert_eq!("C:\\\\Users \\'missing", interpolation.text);
+    }
+
+    #[tokio::test]
+    async fn apply_modifiers() {
+        let mut interpolator = TextInterpolator::default();
+
+        let text = "'noun.cap is 'noun.a. 'adj.upper 'noun.plural.cap! 'verb.cap'ing 'noun.unknown";
+        let interpolated_text = interpolator
+            .interpolate(text, &map_template)
+            .await
+            .unwrap()
+            .text;
+
+        assert_eq!(
+            "Place is a place. AGGRIVATING Places! Runing place.unknown",
+            interpolated_text
+        );
+    }
+
+    #[tokio::test]
+    async fn reuse_bindings() {
+        let mut interpolator = TextInterpolator::default();
+        let count = AtomicUsize::new(0);
+
+        let interpolated_text = interpolator
+            .interpolate(
+                "'name#1 met 'name#2 and 'name#1.upper left. 'name 'name#1^s",
+                &|template: &str| match template {
+                    "name" => Some(format!("name{}", count.fetch_add(1, Ordering::Relaxed) + 1)),
+                    _ => None,
+                },
+            )
+            .await
+            .unwrap()
+            .text;
+
+        assert_eq!(
+            "name1 met name2 and NAME1 left. name3 name1s",
+            interpolated_text
+        );
+
+        let provider = |template: &str| match template {
+            "name" => Some(format!("name{}", count.fetch_add(1, Ordering::Relaxed) + 1)),
+            _ => None,
+        };
+
+        let interpolated_text = interpolator
+            .interpolate("'name#1", &provider)
+            .await
+            .unwrap()
+            .text;
+        assert_eq!("name4", interpolated_text);
+
+        let interpolation = interpolator
+            .continue_interpolation("'name#1 'name#2", &provider)
+            .await
+            .unwrap();
+        assert_eq!("name4 name5", interpolation.text);
+    }
+
+    fn map_exponential_template(template: &str) -> Option<String> {
+        match template {
+            "a" => Some("'b 'b".to_string()),
+            "b" => Some("'c 'c".to_string()),
+            "c" => Some("'d 'd".to_string()),
+            "d" => Some("'e 'e".to_string()),
+            "e" => Some("word".to_string()),
+            "deep" => Some("'deeper".to_string()),
+            "deeper" => Some("'deepest".to_string()),
+            "deepest" => Some("bottom".to_string()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn nesting_depth_limit() {
+        let mut interpolator = TextInterpolator::default();
+        interpolator.limits.max_depth = 1;
+
+        assert!(matches!(
+            interpolator
+                .interpolate("'deep", &map_exponential_template)
+                .await,
+            Err(InterpolationError::NestingDepth(_))
+        ));
+
+        interpolator.limits.max_depth = 2;
+        assert_eq!(
+            interpolator
+                .interpolate("'deep", &map_exponential_template)
+                .await
+                .unwrap()
+                .text,
+            "bottom"
+        );
+    }
+
+    #[tokio::test]
+    async fn expansion_limit() {
+        let mut interpolator = TextInterpolator::default();
+        interpolator.limits.max_expansions = 20;
+
+        assert!(matches!(
+            interpolator
+                .interpolate("'a", &map_exponential_template)
+                .await,
+            Err(InterpolationError::ExpansionLimit(_))
+        ));
+
+        interpolator.limits.max_expansions = 31;
+        assert_eq!(
+            interpolator
+                .interpolate("'a", &map_exponential_template)
+                .await
+                .unwrap()
+                .text
+                .split_whitespace()
+                .count(),
+            16
+        );
+    }
+
+    #[tokio::test]
+    async fn output_size_limit() {
+        let mut interpolator = TextInterpolator::default();
+        interpolator.limits.max_output_bytes = 32;
+
+        assert!(matches!(
+            interpolator
+                .interpolate("'a", &map_exponential_template)
+                .await,
+            Err(InterpolationError::OutputSize(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn record_expansion_tree() {
+        let mut interpolator = TextInterpolator::default();
+
+        let interpolation = interpolator
+            .interpolate(
+                "'sentence 'noun#1 'noun#1.cap",
+                &|template: &str| match template {
+                    "sentence" => Some(Substitute {
+                        text: "A 'adj 'noun".to_string(),
+                        id: Some(1),
+                    }),
+                    _ => map_template(template).map(Substitute::from),
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(interpolation.text, "A aggrivating place place Place");
+
+        let expansions = &interpolation.expansions;
+        assert_eq!(expansions.len(), 3);
+        assert_eq!(expansions[0].template, "sentence");
+        assert_eq!(expansions[0].substitute_id, Some(1));
+        assert_eq!(
+            expansions[0]
+                .children
+                .iter()
+                .map(|e| e.template.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["adj", "noun"]
+        );
+        assert!(!expansions[1].bound);
+        assert!(expansions[2].bound);
+        assert_eq!(expansions[2].output, "Place");
+
+        let spans: Vec<&str> = expansions
+            .iter()
+            .chain(&expansions[0].children)
+            .map(|e| &interpolation.text[e.span.clone()])
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                "A aggrivating place",
+                "place",
+                "Place",
+                "aggrivating",
+                "place"
+            ]
+        );
+
+        let interpolation = interpolator
+            .interpolate("don\\'t \\^_^ 'noun", &map_template)
+            .await
+            .unwrap();
+        let expansion = &interpolation.expansions[0];
+        assert_eq!(&interpolation.text[expansion.span.clone()], "place");
+    }
 }
@@ -0,0 +1,300 @@
+//! Inline choices such as **[red|green|blue]** that are replaced by one of their alternatives.
+//!
+//! Alternatives may be weighted by ending them with a colon and a number, **[common:3|rare:1]**,
+//! and may contain other choices. Brackets without a separator and choices whose alternatives all
+//! have a weight of 0 are left as ordinary text.
+//!
+//! Embedded code such as **{add(1, 2)}** is left untouched, so brackets inside of it are never
+//! read as choices.
+//!
+//! Brackets and separators preceded by a \\ are ordinary characters, **\\[a|b]** is output as
+//! **[a|b]**. A pair of them is one ordinary \\, so **\\\\[a|b]** is output as a \\ followed by
+//! **a** or **b**.
+
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    Rng,
+};
+
+use super::defaults::{find_removed_escapes, halve_trailing_escapes, is_escaped, remove_escapes};
+
+pub const CHOICE_START: char = '[';
+pub const CHOICE_END: char = ']';
+pub const CHOICE_SEPARATOR: char = '|';
+pub const WEIGHT_SEPARATOR: char = ':';
+/// Characters that are used as ordinary characters when escaped.
+const CHOICE_CHARACTERS: [char; 3] = [CHOICE_START, CHOICE_END, CHOICE_SEPARATOR];
+const CODE_START: char = '{';
+const CODE_END: char = '}';
+
+/// Replaces every inline choice with one of its alternatives chosen at random and removes the
+/// escape character from escaped brackets and separators.
+///
+/// # Examples
+///
+/// ```
+/// use text_interpolator::choices::expand_choices;
+///
+/// let text = expand_choices("I like [red|blue:0] and [citation]", &mut rand::thread_rng());
+///
+/// assert_eq!(text, "I like red and [citation]");
+///
+/// let text = expand_choices("\\[red|blue]", &mut rand::thread_rng());
+///
+/// assert_eq!(text, "[red|blue]");
+/// ```
+pub fn expand_choices(text: &str, rng: &mut impl Rng) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = find_unescaped(rest, CHOICE_START) {
+        output.push_str(&unescape_choices(halve_trailing_escapes(&rest[..start])));
+        rest = &rest[start..];
+
+        let Some(end) = find_choice_end(rest) else {
+            output.push(CHOICE_START);
+            rest = &rest[1..];
+            continue;
+        };
+
+        let inner = &rest[1..end];
+        let alternatives = split_alternatives(inner);
+
+        match choose_alternative(&alternatives, rng) {
+            Some(choice) if alternatives.len() >= 2 => {
+                output.push_str(&expand_choices(choice, rng));
+            }
+            _ => {
+                output.push(CHOICE_START);
+                output.push_str(&expand_choices(halve_trailing_escapes(inner), rng));
+                output.push(CHOICE_END);
+            }
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    output.push_str(&unescape_choices(rest));
+    output
+}
+
+/// Iterates over the characters that aren't inside embedded code, skipping the braces of the
+/// code as well.
+fn outside_code(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut depth = 0;
+
+    text.char_indices().filter(move |(_, c)| match *c {
+        CODE_START => {
+            depth += 1;
+            false
+        }
+        CODE_END if depth > 0 => {
+            depth -= 1;
+            false
+        }
+        _ => depth == 0,
+    })
+}
+
+/// Finds the byte index of the first occurrence of the character that isn't escaped or inside of
+/// embedded code.
+fn find_unescaped(text: &str, character: char) -> Option<usize> {
+    outside_code(text)
+        .find(|(i, c)| *c == character && !is_escaped(text, *i))
+        .map(|(i, _)| i)
+}
+
+/// Removes the escape character from every escaped bracket and separator outside of embedded
+/// code and collapses each pair of escape characters in front of one into one.
+fn unescape_choices(text: &str) -> String {
+    remove_escapes(
+        text,
+        &find_removed_escapes(outside_code(text), &CHOICE_CHARACTERS),
+    )
+}
+
+/// Finds the index of the bracket closing the choice at the start of the text.
+fn find_choice_end(text: &str) -> Option<usize> {
+    let mut depth = 0;
+
+    for (i, c) in outside_code(text) {
+        if is_escaped(text, i) {
+            continue;
+        }
+
+        match c {
+            CHOICE_START => depth += 1,
+            CHOICE_END => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Splits a choice into its alternatives, ignoring separators inside nested choices.
+fn split_alternatives(inner: &str) -> Vec<&str> {
+    let mut alternatives = Vec::new();
+    let mut depth = 0;
+    let mut alternative_start = 0;
+
+    for (i, c) in outside_code(inner) {
+        if is_escaped(inner, i) {
+            continue;
+        }
+
+        match c {
+            CHOICE_START => depth += 1,
+            CHOICE_END => depth -= 1,
+            CHOICE_SEPARATOR if depth == 0 => {
+                alternatives.push(halve_trailing_escapes(&inner[alternative_start..i]));
+                alternative_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    alternatives.push(halve_trailing_escapes(&inner[alternative_start..]));
+    alternatives
+}
+
+/// Splits the weight from the end of an alternative. Alternatives without a weight have a weight
+/// of 1.
+fn split_weight(alternative: &str) -> (&str, u32) {
+    match alternative.rsplit_once(WEIGHT_SEPARATOR) {
+        Some((choice, weight))
+            if !weight.is_empty() && weight.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            match weight.parse() {
+                Ok(weight) => (choice, weight),
+                Err(_) => (alternative, 1),
+            }
+        }
+        _ => (alternative, 1),
+    }
+}
+
+/// Picks an alternative by weight. Returns None if every weight is 0, so the caller can keep the
+/// choice as ordinary text instead of dropping it.
+///
+/// Weights are summed as u64 so large u32 weights can't overflow the total.
+fn choose_alternative<'a>(alternatives: &[&'a str], rng: &mut impl Rng) -> Option<&'a str> {
+    let (choices, weights): (Vec<&str>, Vec<u64>) = alternatives
+        .iter()
+        .map(|alternative| split_weight(alternative))
+        .map(|(choice, weight)| (choice, u64::from(weight)))
+        .unzip();
+
+    let distribution = WeightedIndex::new(&weights).ok()?;
+    Some(choices[distribution.sample(rng)])
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn expand_weighted_choices() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(expand_choices("[common:0|rare:1]", &mut rng), "rare");
+        assert_eq!(expand_choices("a [b:0|c:0] d", &mut rng), "a [b:0|c:0] d");
+        assert_eq!(expand_choices("[1:2|1:2]", &mut rng), "1");
+
+        let mut seen = Vec::new();
+        for _ in 0..100 {
+            let color = expand_choices("[red|green|blue]", &mut rng);
+            if !seen.contains(&color) {
+                seen.push(color);
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, vec!["blue", "green", "red"]);
+    }
+
+    #[test]
+    fn expand_nested_choices() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(
+            expand_choices("[[^noun|^noun:2]|^noun [x|x]:1]", &mut rng)
+                .split_whitespace()
+                .next(),
+            Some("^noun")
+        );
+        assert_eq!(
+            expand_choices("[dragon fruit|dragon fruit] [x|[y|y]:0]", &mut rng),
+            "dragon fruit x"
+        );
+    }
+
+    #[test]
+    fn keep_brackets_without_choices() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(expand_choices("[citation]", &mut rng), "[citation]");
+        assert_eq!(
+            expand_choices("[link](url) [x|x]", &mut rng),
+            "[link](url) x"
+        );
+        assert_eq!(expand_choices("[a|b", &mut rng), "[a|b");
+        assert_eq!(expand_choices("[ [a|a]", &mut rng), "[ a");
+        assert_eq!(expand_choices("]|[", &mut rng), "]|[");
+    }
+
+    #[test]
+    fn escaped_choices() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(expand_choices("\\[x|y]", &mut rng), "[x|y]");
+        assert_eq!(expand_choices("[x\\|y|x\\|y]", &mut rng), "x|y");
+        assert_eq!(expand_choices("[x\\]|x\\]]", &mut rng), "x]");
+        assert_eq!(expand_choices("[\\[x|\\[x]", &mut rng), "[x");
+        assert_eq!(expand_choices("don\\'t \\[a\\|b", &mut rng), "don\\'t [a|b");
+        assert_eq!(expand_choices("\\\\[x|x]", &mut rng), "\\x");
+        assert_eq!(expand_choices("\\\\\\[x|x]", &mut rng), "\\[x|x]");
+        assert_eq!(expand_choices("[x\\\\|x\\\\]", &mut rng), "x\\");
+        assert_eq!(expand_choices("a\\\\ [b\\\\]", &mut rng), "a\\\\ [b\\]");
+    }
+
+    #[test]
+    fn skip_choices_in_code() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(
+            expand_choices("{get_sub(\"[a|b]\")} [x|x]", &mut rng),
+            "{get_sub(\"[a|b]\")} x"
+        );
+        assert_eq!(
+            expand_choices("[{concat(\"|\", \"]\")}|{concat(\"|\", \"]\")}]", &mut rng),
+            "{concat(\"|\", \"]\")}"
+        );
+        assert_eq!(
+            expand_choices("{ {\"\\[\"} } \\[x]", &mut rng),
+            "{ {\"\\[\"} } [x]"
+        );
+    }
+
+    #[test]
+    fn maximum_choice_weights() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut seen = Vec::new();
+        for _ in 0..50 {
+            let choice = expand_choices("[a:4294967295|b:4294967295|c:1]", &mut rng);
+            if !seen.contains(&choice) {
+                seen.push(choice);
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, vec!["a", "b"]);
+        assert_eq!(expand_choices("[a:4294967295|b:0]", &mut rng), "a");
+    }
+}
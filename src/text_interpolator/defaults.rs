@@ -1,14 +1,157 @@
-use crate::text_interpolator::TemplateSplit;
+use crate::text_interpolator::{ModifierFn, TemplateSplit};
 
 pub const TEMPLATE_APOSTROPHE: char = '\'';
 pub const TEMPLATE_CARROT: char = '^';
 pub const TEMPLATE_BACK_TICK: char = '`';
 pub const TEMPLATE_HEADERS: [char; 3] = [TEMPLATE_APOSTROPHE, TEMPLATE_CARROT, TEMPLATE_BACK_TICK];
+/// Separates a template from the label its substitute is bound to.
+pub const BINDING_SEPARATOR: char = '#';
+/// Separates a template from the modifiers applied to its substitute.
+pub const MODIFIER_SEPARATOR: char = '.';
+/// Placed directly before a template header to use the header as an ordinary character.
+pub const TEMPLATE_ESCAPE: char = '\\';
+
+/// Returns true if the character at `index` is preceded by an odd number of escape characters.
+///
+/// An escape character escapes the one after it, so in **\\\\'noun** the escape characters
+/// escape each other and the header is not escaped.
+///
+/// # Examples
+///
+/// ```
+/// use text_interpolator::defaults::is_escaped;
+///
+/// assert!(is_escaped("don\\'t", 4));
+/// assert!(!is_escaped("'noun", 0));
+/// assert!(!is_escaped("\\\\'noun", 2));
+/// assert!(is_escaped("\\\\\\'noun", 3));
+/// ```
+pub fn is_escaped(text: &str, index: usize) -> bool {
+    let escapes = text[..index]
+        .chars()
+        .rev()
+        .take_while(|c| *c == TEMPLATE_ESCAPE)
+        .count();
+
+    escapes % 2 == 1
+}
+
+/// Finds the byte index of the first template header that isn't escaped.
+pub fn find_template_header(text: &str) -> Option<usize> {
+    text.char_indices()
+        .find(|(i, c)| TEMPLATE_HEADERS.contains(c) && !is_escaped(text, *i))
+        .map(|(i, _)| i)
+}
+
+/// Escapes every template header so the text is never read as a template.
+///
+/// Escape characters already in front of a header are doubled so they stay ordinary characters.
+///
+/// # Examples
+///
+/// ```
+/// use text_interpolator::defaults::escape_template_headers;
+///
+/// assert_eq!(escape_template_headers("don't"), "don\\'t");
+/// assert_eq!(escape_template_headers("\\'"), "\\\\\\'");
+/// ```
+pub fn escape_template_headers(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut escapes = 0;
+
+    for c in text.chars() {
+        if TEMPLATE_HEADERS.contains(&c) {
+            output.extend(std::iter::repeat_n(TEMPLATE_ESCAPE, escapes + 1));
+        }
+
+        escapes = if c == TEMPLATE_ESCAPE { escapes + 1 } else { 0 };
+        output.push(c);
+    }
+
+    output
+}
+
+/// Finds the byte index of every escape character removed by unescaping the special characters.
+///
+/// Each pair of escape characters directly before a special character is one ordinary escape
+/// character and an escape character left over escapes the special character, matching
+/// [`is_escaped`]. Escape characters anywhere else are left alone.
+///
+/// Characters skipped by the iterator end a run of escape characters.
+pub fn find_removed_escapes(
+    chars: impl Iterator<Item = (usize, char)>,
+    special: &[char],
+) -> Vec<usize> {
+    let mut removed = Vec::new();
+    let mut run = Vec::new();
+    let mut next_index = 0;
+
+    for (i, c) in chars {
+        if i != next_index {
+            run.clear();
+        }
+        next_index = i + c.len_utf8();
+
+        if c == TEMPLATE_ESCAPE {
+            run.push(i);
+            continue;
+        }
+
+        if special.contains(&c) {
+            removed.extend_from_slice(&run[..run.len().div_ceil(2)]);
+        }
+        run.clear();
+    }
+
+    removed
+}
+
+/// Removes the characters at the byte indices found by [`find_removed_escapes`].
+pub fn remove_escapes(text: &str, removed: &[usize]) -> String {
+    text.char_indices()
+        .filter(|(i, _)| removed.binary_search(i).is_err())
+        .map(|(_, c)| c)
+        .collect()
+}
+
+/// Halves the escape characters at the end of text that's followed by an unescaped special
+/// character, since each pair of them is one ordinary escape character.
+///
+/// # Examples
+///
+/// ```
+/// use text_interpolator::defaults::halve_trailing_escapes;
+///
+/// assert_eq!(halve_trailing_escapes("a\\\\"), "a\\");
+/// ```
+pub fn halve_trailing_escapes(text: &str) -> &str {
+    let escapes = text.len() - text.trim_end_matches(TEMPLATE_ESCAPE).len();
+    &text[..text.len() - escapes / 2]
+}
+
+/// Removes the escape character from every escaped template header and collapses each pair of
+/// escape characters in front of a header into one.
+///
+/// # Examples
+///
+/// ```
+/// use text_interpolator::defaults::unescape_template_headers;
+///
+/// assert_eq!(unescape_template_headers("don\\'t \\^_^"), "don't ^_^");
+/// assert_eq!(unescape_template_headers("\\\\\\'s \\\\ \\"), "\\'s \\\\ \\");
+/// ```
+pub fn unescape_template_headers(text: &str) -> String {
+    remove_escapes(
+        text,
+        &find_removed_escapes(text.char_indices(), &TEMPLATE_HEADERS),
+    )
+}
 
 /// Checks if a string is a template.
 ///
 /// The default implementation considers a string starting with any character in the
-/// TEMPLATE_HEADERS array to be a template.
+/// TEMPLATE_HEADERS array to be a template. Escaped headers such as \\' start with the escape
+/// character so are never templates.
 ///
 /// Returns None if the string is empty or if the first character is not found in the
 /// TEMPLATE_HEADERS array.
@@ -38,6 +181,8 @@ pub fn is_template(text: &str) -> bool {
 /// Then it will split the text from the template character ^ up to any non alphanumeric character
 /// such that the template portion in this case will be verb and the suffix portion will be ing"
 ///
+/// Template headers preceded by TEMPLATE_ESCAPE are skipped.
+///
 /// # Examples
 ///
 /// ```
@@ -55,7 +200,10 @@ pub fn extract_template<'a>(embedded_template: &'a str) -> TemplateSplit<'a> {
 
     let mut split_char = ' ';
 
-    match embedded_template.split_once(|c| TEMPLATE_HEADERS.contains(&c)) {
+    let header_split = find_template_header(embedded_template)
+        .map(|i| (&embedded_template[..i], &embedded_template[i + 1..]));
+
+    match header_split {
         Some(split) => match split.1.split_once(|c: char| {
             split_char = c;
             !c.is_alphanumeric()
@@ -90,6 +238,93 @@ pub fn extract_template<'a>(embedded_template: &'a str) -> TemplateSplit<'a> {
     }
 }
 
+/// Finds one of the default modifiers.
+///
+/// * **cap** capitalizes the first letter
+/// * **upper** and **lower** change the case of every letter
+/// * **a** adds the article "a" or "an"
+/// * **plural** pluralizes the last word
+///
+/// # Examples
+///
+/// ```
+/// use text_interpolator::defaults::find_modifier;
+///
+/// assert_eq!(find_modifier("cap").unwrap()("apple"), "Apple");
+/// assert!(find_modifier("unknown").is_none());
+/// ```
+pub fn find_modifier(name: &str) -> Option<ModifierFn> {
+    match name {
+        "cap" => Some(capitalize),
+        "upper" => Some(uppercase),
+        "lower" => Some(lowercase),
+        "a" => Some(add_article),
+        "plural" => Some(pluralize),
+        _ => None,
+    }
+}
+
+pub fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+pub fn uppercase(text: &str) -> String {
+    text.to_uppercase()
+}
+
+pub fn lowercase(text: &str) -> String {
+    text.to_lowercase()
+}
+
+/// Adds "an" before text starting with a vowel and "a" before anything else.
+pub fn add_article(text: &str) -> String {
+    match text.chars().next() {
+        Some(c) if "aeiouAEIOU".contains(c) => format!("an {}", text),
+        Some(_) => format!("a {}", text),
+        None => String::new(),
+    }
+}
+
+/// Pluralizes the last word of the text using common English spelling rules.
+pub fn pluralize(text: &str) -> String {
+    let word_start = text
+        .rfind(char::is_whitespace)
+        .map(|i| i + 1)
+        .unwrap_or_default();
+    let word = &text[word_start..];
+    let lowercase_word = word.to_lowercase();
+
+    let (stem, ending) = if word.is_empty() {
+        (word, "")
+    } else if ["s", "x", "z", "ch", "sh"]
+        .iter()
+        .any(|ending| lowercase_word.ends_with(ending))
+    {
+        (word, "es")
+    } else if lowercase_word.ends_with('y')
+        && !["ay", "ey", "iy", "oy", "uy"]
+            .iter()
+            .any(|ending| lowercase_word.ends_with(ending))
+    {
+        (&word[..word.len() - 1], "ies")
+    } else {
+        (word, "s")
+    };
+
+    let ending = if word.len() > 1 && word.chars().all(|c| !c.is_lowercase()) {
+        ending.to_uppercase()
+    } else {
+        ending.to_string()
+    };
+
+    format!("{}{}{}", &text[..word_start], stem, ending)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -157,4 +392,35 @@ mod tests {
         assert_eq!("noun", extrated_template.suffix);
         assert_eq!("noun", extrated_template.template);
     }
+
+    #[test]
+    fn template_extraction_with_escaped_header() {
+        let extrated_template = extract_template("don\\'t");
+        dbg!(&extrated_template);
+        assert_eq!("", extrated_template.template);
+
+        let extrated_template = extract_template("\\^\\^'noun");
+        dbg!(&extrated_template);
+        assert_eq!("\\^\\^", extrated_template.prefix);
+        assert_eq!("noun", extrated_template.template);
+
+        let extrated_template = extract_template("'noun\\'s");
+        dbg!(&extrated_template);
+        assert_eq!("noun", extrated_template.template);
+        assert_eq!("\\'s", extrated_template.suffix);
+    }
+
+    #[test]
+    fn default_modifiers() {
+        assert_eq!(capitalize("élan vital"), "Élan vital");
+        assert_eq!(add_article("apple"), "an apple");
+        assert_eq!(add_article("Pear"), "a Pear");
+        assert_eq!(pluralize("dragon fruit"), "dragon fruits");
+        assert_eq!(pluralize("box"), "boxes");
+        assert_eq!(pluralize("church"), "churches");
+        assert_eq!(pluralize("berry"), "berries");
+        assert_eq!(pluralize("day"), "days");
+        assert_eq!(pluralize("CAT"), "CATS");
+        assert_eq!(pluralize(""), "");
+    }
 }